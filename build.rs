@@ -1,6 +1,13 @@
 // build script for cargo, will configure tonic build
 
+use std::env;
+use std::path::PathBuf;
+
 fn main() -> Result<(), Box<dyn std::error::Error>> {
-    tonic_build::compile_protos("proto/tasks.proto")?;
+    // The encoded descriptors of the protos are served by the master's reflection service
+    let out_dir = PathBuf::from(env::var("OUT_DIR")?);
+    tonic_build::configure()
+        .file_descriptor_set_path(out_dir.join("tasks_descriptor.bin"))
+        .compile(&["proto/tasks.proto", "proto/admin.proto"], &["proto"])?;
     Ok(())
 }
@@ -1,5 +1,9 @@
-use mongodb::bson::doc;
-use mongodb::Client;
+use mongodb::bson::{doc, Document};
+use mongodb::error::ErrorKind;
+use mongodb::options::{
+    FindOneAndUpdateOptions, IndexOptions, InsertManyOptions, ReplaceOptions, ReturnDocument,
+};
+use mongodb::{Client, IndexModel};
 use std::collections::HashMap;
 
 use serde::{Deserialize, Serialize};
@@ -11,6 +15,16 @@ struct MasterState {
     n_map: i64,
     n_reduce: i64,
     map_tasks_left: i64,
+    options: Document,
+}
+
+// Error code of writes rejected by a unique index
+const DUPLICATE_KEY: i32 = 11000;
+
+// Name of the database holding the state of a job. Every job gets its own database, so that a
+// restarted master can find the state of the job it is resuming.
+pub fn job_db_name(job_id: &str) -> String {
+    format!("mrlite-{}", job_id)
 }
 
 // Creates a mongodb collection
@@ -18,7 +32,7 @@ pub async fn create_collection(client: &Client, db_name: &str, coll_name: &str)
     let db = client.database(db_name);
     for collection_name in db.list_collection_names(None).await.unwrap() {
         if collection_name == coll_name {
-            eprintln!("Collection {} already exists. Exiting.", collection_name);
+            eprintln!("Collection {} already exists.", collection_name);
             return;
         };
     }
@@ -32,7 +46,26 @@ pub async fn drop_collection(client: &Client, db_name: &str, coll_name: &str) {
     coll.drop(None).await.expect("Could not drop collection.");
 }
 
-// Initializes master state
+// Drops a whole mongodb database
+pub async fn drop_database(client: &Client, db_name: &str) {
+    let db = client.database(db_name);
+    db.drop(None).await.expect("Could not drop database.");
+}
+
+// Makes `field` unique across the records of a collection, so that initializing the same records
+// twice cannot duplicate them
+pub async fn create_unique_index(client: &Client, db_name: &str, coll_name: &str, field: &str) {
+    let db = client.database(db_name);
+    let coll = db.collection::<Document>(coll_name);
+
+    let index = IndexModel::builder()
+        .keys(doc! {field.to_string(): 1})
+        .options(IndexOptions::builder().unique(true).build())
+        .build();
+    coll.create_index(index, None).await.unwrap();
+}
+
+// Initializes master state, replacing any existing record of the same name
 pub async fn init_master_state(
     client: &Client,
     db_name: &str,
@@ -40,24 +73,30 @@ pub async fn init_master_state(
     record_name: &str,
     n_map: i64,
     n_reduce: i64,
+    options: Document,
 ) {
     let db = client.database(db_name);
     let coll = db.collection(coll_name);
 
-    coll.insert_one(
+    let filter = doc! {"name": record_name.to_string()};
+    let upsert = ReplaceOptions::builder().upsert(true).build();
+    coll.replace_one(
+        filter,
         MasterState {
             name: record_name.to_string(),
             n_map: n_map,
             n_reduce: n_reduce,
             map_tasks_left: n_map,
+            options,
         },
-        None,
+        upsert,
     )
     .await
     .unwrap();
 }
 
-// Initializes map and reduce tasks state (requires one call for each)
+// Initializes map and reduce tasks state (requires one call for each). Tasks that already exist
+// are left as they are, provided the collection has a unique index on "name".
 pub async fn init_tasks(
     client: &Client,
     db_name: &str,
@@ -77,10 +116,29 @@ pub async fn init_tasks(
             "is_map": task_state.1,
             "tasknum": tasknum,
             "done": false,
+            "attempts": 0_i64,
+            "user_failures": 0_i64,
         });
         i += 1;
     }
-    coll.insert_many(vec, None).await.unwrap();
+
+    let unordered = InsertManyOptions::builder().ordered(false).build();
+    if let Err(err) = coll.insert_many(vec, unordered).await {
+        let duplicates_only = match *err.kind {
+            ErrorKind::BulkWrite(ref failure) => {
+                failure.write_concern_error.is_none()
+                    && failure
+                        .write_errors
+                        .iter()
+                        .flatten()
+                        .all(|write_error| write_error.code == DUPLICATE_KEY)
+            }
+            _ => false,
+        };
+        if !duplicates_only {
+            panic!("Could not initialize tasks: {}", err);
+        }
+    }
 }
 
 // Gets value of some integer field of the current (master) state
@@ -103,6 +161,36 @@ pub async fn get_val(
     }
 }
 
+// Returns a whole record, such as the current (master) state
+pub async fn get_record(
+    client: &Client,
+    db_name: &str,
+    coll_name: &str,
+    record_name: &str,
+) -> Option<Document> {
+    let db = client.database(db_name);
+    let coll = db.collection::<Document>(coll_name);
+
+    let filter = doc! {"name": record_name.to_string()};
+    coll.find_one(Some(filter), None).await.unwrap()
+}
+
+// Gets some document valued field of the current (master) state
+pub async fn get_doc(
+    client: &Client,
+    db_name: &str,
+    coll_name: &str,
+    record_name: &str,
+    field: &str,
+) -> Option<Document> {
+    let db = client.database(db_name);
+    let coll = db.collection::<Document>(coll_name);
+
+    let filter = doc! {"name": record_name.to_string()};
+    let res = coll.find_one(Some(filter), None).await.unwrap();
+    res.and_then(|state| state.get_document(field).ok().cloned())
+}
+
 // Returns a task tuple given a task name
 pub async fn get_task(
     client: &Client,
@@ -134,6 +222,27 @@ pub async fn get_task(
     }
 }
 
+// Returns every task in a collection
+pub async fn get_tasks(client: &Client, db_name: &str, coll_name: &str) -> Vec<Document> {
+    let db = client.database(db_name);
+    let coll = db.collection::<Document>(coll_name);
+
+    let mut cursor = coll.find(None, None).await.unwrap();
+    let mut tasks = Vec::new();
+    while cursor.advance().await.unwrap() {
+        tasks.push(cursor.deserialize_current().unwrap());
+    }
+    tasks
+}
+
+// Counts the tasks in a collection that match a filter
+pub async fn count_tasks(client: &Client, db_name: &str, coll_name: &str, filter: Document) -> u64 {
+    let db = client.database(db_name);
+    let coll = db.collection::<Document>(coll_name);
+
+    coll.count_documents(Some(filter), None).await.unwrap()
+}
+
 // Updates some integer count in the current state
 pub async fn update_count(
     client: &Client,
@@ -151,6 +260,109 @@ pub async fn update_count(
     coll.update_one(filter, update, None).await.unwrap();
 }
 
+// Updates some string field in the current state
+pub async fn update_str(
+    client: &Client,
+    db_name: &str,
+    coll_name: &str,
+    record_name: &str,
+    field: &str,
+    new_val: &str,
+) {
+    let db = client.database(db_name);
+    let coll = db.collection::<mongodb::bson::Document>(coll_name);
+
+    let filter = doc! {"name": record_name.to_string()};
+    let update = doc! {"$set": {field.to_string(): new_val.to_string()}};
+    coll.update_one(filter, update, None).await.unwrap();
+}
+
+// Updates some boolean field in the current state
+pub async fn update_bool(
+    client: &Client,
+    db_name: &str,
+    coll_name: &str,
+    record_name: &str,
+    field: &str,
+    new_val: bool,
+) {
+    let db = client.database(db_name);
+    let coll = db.collection::<mongodb::bson::Document>(coll_name);
+
+    let filter = doc! {"name": record_name.to_string()};
+    let update = doc! {"$set": {field.to_string(): new_val}};
+    coll.update_one(filter, update, None).await.unwrap();
+}
+
+// Atomically adds `amount` to some integer count in a record, returning the new value
+pub async fn increment_count(
+    client: &Client,
+    db_name: &str,
+    coll_name: &str,
+    record_name: &str,
+    field: &str,
+    amount: i64,
+) -> i64 {
+    let db = client.database(db_name);
+    let coll = db.collection::<mongodb::bson::Document>(coll_name);
+
+    let filter = doc! {"name": record_name.to_string()};
+    let update = doc! {"$inc": {field.to_string(): amount}};
+    let options = FindOneAndUpdateOptions::builder()
+        .return_document(ReturnDocument::After)
+        .build();
+    let res = coll
+        .find_one_and_update(filter, update, options)
+        .await
+        .unwrap()
+        .expect("Record to increment should exist.");
+    res.get_i64(field).unwrap()
+}
+
+// Atomically adds several amounts to the counts in a record
+pub async fn increment_counts(
+    client: &Client,
+    db_name: &str,
+    coll_name: &str,
+    record_name: &str,
+    amounts: &[(&str, i64)],
+) {
+    let db = client.database(db_name);
+    let coll = db.collection::<Document>(coll_name);
+
+    let filter = doc! {"name": record_name.to_string()};
+    let mut inc = Document::new();
+    for (field, amount) in amounts {
+        inc.insert(field.to_string(), *amount);
+    }
+    coll.update_one(filter, doc! {"$inc": inc}, None)
+        .await
+        .unwrap();
+}
+
+// Atomically applies the `$set` of `new_vals` to some task, but only if the task currently
+// matches `state`. Returns the task as it was before the update, or None if it did not match.
+pub async fn transition_task(
+    client: &Client,
+    db_name: &str,
+    coll_name: &str,
+    task_name: &str,
+    mut state: Document,
+    new_vals: Document,
+) -> Option<Document> {
+    let db = client.database(db_name);
+    let coll = db.collection::<Document>(coll_name);
+
+    state.insert("name", task_name.to_string());
+    let update = doc! {"$set": new_vals};
+    let options = FindOneAndUpdateOptions::builder()
+        .return_document(ReturnDocument::Before)
+        .build();
+    coll.find_one_and_update(state, update, options)
+        .await
+        .unwrap()
+}
+
 // Updates the assigned value in some task
 pub async fn update_assigned(
     client: &Client,
@@ -167,6 +379,92 @@ pub async fn update_assigned(
     coll.update_one(filter, update, None).await.unwrap();
 }
 
+// Records a failure while processing the record at `offset` of some task, returning how many
+// times that record has failed
+pub async fn increment_record_failures(
+    client: &Client,
+    db_name: &str,
+    coll_name: &str,
+    task_name: &str,
+    offset: i64,
+) -> i64 {
+    let db = client.database(db_name);
+    let coll = db.collection::<mongodb::bson::Document>(coll_name);
+
+    let filter = doc! {"name": task_name.to_string()};
+    let update = doc! {"$inc": {format!("record_failures.{}", offset): 1_i64}};
+    let options = FindOneAndUpdateOptions::builder()
+        .return_document(ReturnDocument::After)
+        .build();
+    let res = coll
+        .find_one_and_update(filter, update, options)
+        .await
+        .unwrap()
+        .expect("Task should exist.");
+    res.get_document("record_failures")
+        .unwrap()
+        .get_i64(offset.to_string())
+        .unwrap()
+}
+
+// Returns the offsets of the records of some task that have failed at least `threshold` times
+pub async fn get_bad_records(
+    client: &Client,
+    db_name: &str,
+    coll_name: &str,
+    task_name: &str,
+    threshold: i64,
+) -> Vec<i64> {
+    let db = client.database(db_name);
+    let coll = db.collection::<mongodb::bson::Document>(coll_name);
+
+    let filter = doc! {"name": task_name.to_string()};
+    let res = coll.find_one(Some(filter), None).await.unwrap();
+
+    let Some(failures) = res
+        .as_ref()
+        .and_then(|task| task.get_document("record_failures").ok())
+    else {
+        return Vec::new();
+    };
+    failures
+        .iter()
+        .filter(|(_, count)| count.as_i64().unwrap_or(0) >= threshold)
+        .filter_map(|(offset, _)| offset.parse().ok())
+        .collect()
+}
+
+// Records the most recent error reported for some task
+pub async fn update_last_error(
+    client: &Client,
+    db_name: &str,
+    coll_name: &str,
+    task_name: &str,
+    error: &str,
+) {
+    let db = client.database(db_name);
+    let coll = db.collection::<mongodb::bson::Document>(coll_name);
+
+    let filter = doc! {"name": task_name.to_string()};
+    let update = doc! {"$set": {"last_error".to_string(): error.to_string()}};
+    coll.update_one(filter, update, None).await.unwrap();
+}
+
+// Marks every task that was assigned but never finished as idle again, returning how many were
+// reset. Used when resuming a job whose previous master crashed, since the workers running those
+// tasks are gone.
+pub async fn reset_unfinished(client: &Client, db_name: &str, coll_name: &str) -> u64 {
+    let db = client.database(db_name);
+    let coll = db.collection::<Document>(coll_name);
+
+    let filter = doc! {"is_assigned": true, "done": false};
+    let update = doc! {"$set": {"is_assigned": false}};
+    coll.update_many(filter, update, None)
+        .await
+        .unwrap()
+        .modified_count
+}
+
 // Updates done value of some task
 pub async fn update_done(
     client: &Client,
@@ -218,7 +516,16 @@ mod tests {
         let n_map: i64 = 15;
         let n_reduce: i64 = 1;
         let new_n_map: i64 = 34;
-        init_master_state(&client, db_name, coll_name, "test_update", n_map, n_reduce).await;
+        init_master_state(
+            &client,
+            db_name,
+            coll_name,
+            "test_update",
+            n_map,
+            n_reduce,
+            doc! {},
+        )
+        .await;
         update_count(
             &client,
             db_name,
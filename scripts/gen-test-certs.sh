@@ -0,0 +1,36 @@
+#!/bin/sh
+# Generates a certificate authority and a certificate for the master signed by it, for trying
+# out TLS locally. Not for production use: the keys are written unencrypted.
+#
+# usage: scripts/gen-test-certs.sh [<dir>] [<name>...]
+#
+# Writes ca.pem, master.pem and master.key to <dir> (default ./certs). The master's certificate
+# is issued to localhost and any further names given, e.g. the host the master runs on.
+set -eu
+
+dir=${1:-certs}
+[ $# -gt 0 ] && shift
+sans="DNS:localhost"
+for name in "$@"; do
+	sans="$sans,DNS:$name"
+done
+
+mkdir -p "$dir"
+cd "$dir"
+
+openssl genpkey -algorithm EC -pkeyopt ec_paramgen_curve:P-256 -out ca.key
+openssl req -x509 -new -key ca.key -sha256 -days 365 -subj "/CN=mrlite test CA" \
+	-addext "basicConstraints=critical,CA:TRUE" \
+	-addext "keyUsage=critical,keyCertSign" \
+	-out ca.pem
+
+openssl genpkey -algorithm EC -pkeyopt ec_paramgen_curve:P-256 -out master.key
+openssl req -new -key master.key -subj "/CN=localhost" -out master.csr
+printf 'basicConstraints=CA:FALSE\nsubjectAltName=%s\nextendedKeyUsage=serverAuth\n' "$sans" \
+	> master.ext
+openssl x509 -req -in master.csr -CA ca.pem -CAkey ca.key -CAcreateserial -sha256 -days 365 \
+	-extfile master.ext -out master.pem
+rm master.csr master.ext ca.srl
+chmod 600 ca.key master.key
+
+echo "Wrote $dir/ca.pem, $dir/master.pem and $dir/master.key"
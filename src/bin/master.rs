@@ -1,205 +1,2375 @@
 extern crate mongo_utils;
 
-use mongo_utils::update_assigned;
+use admin::admin_server::{Admin, AdminServer};
+use admin::job_event::Event;
+use admin::{
+    CancelJobRequest, CancelJobResponse, DrainWorkerRequest, DrainWorkerResponse,
+    GetJobStatusRequest, JobEvent, JobFinished, JobState, JobStatus, JobSummary, ListJobsRequest,
+    ListJobsResponse, PhaseChanged, PhaseStatus, SubmitJobRequest, SubmitJobResponse, TaskAssigned,
+    TaskCompleted, TaskFailed, TaskReassigned, TaskStatus, WatchJobRequest, WorkerStatus,
+};
+use mongodb::bson::doc;
 use mongodb::{options::ClientOptions, Client};
+use mrlite::job::{self, IfExists, Job, JobOptions, MasterArgs};
+use mrlite::location::Location;
+use mrlite::registry::{JobTask, TaskRef, WorkerRegistry};
+use mrlite::scheduler::{PhaseProgress, Scheduler, TaskState};
+use mrlite::shuffle::{MapOutput, MapOutputs};
+use mrlite::transport::{self, CheckToken, ListenAddr, ServerTls};
+use mrlite::version;
 use std::collections::HashMap;
 use std::env;
-use std::process::exit;
+use std::fs;
+use std::io::Read;
+use std::path::{Component, Path, PathBuf};
+use std::process::{self, exit};
 use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tasks::task_response::Action;
 use tasks::task_server::{Task, TaskServer};
-use tasks::{TaskRequest, TaskResponse};
+use tasks::{
+    Exit, HeartbeatRequest, HeartbeatResponse, JobInfo, MapTask, ReduceTask, RegisterRequest,
+    RegisterResponse, TaskOutcome, TaskReport, TaskReportResponse, TaskRequest, TaskResponse, Wait,
+};
+use tokio::sync::{broadcast, mpsc};
+use tokio_stream::wrappers::ReceiverStream;
 use tonic::{transport::Server, Request, Response, Status};
+use tonic_health::server::HealthReporter;
+use tonic_health::ServingStatus;
 
 pub mod tasks {
     tonic::include_proto!("tasks");
+
+    pub const FILE_DESCRIPTOR_SET: &[u8] = tonic::include_file_descriptor_set!("tasks_descriptor");
+}
+
+pub mod admin {
+    tonic::include_proto!("admin");
 }
 
-static DB_NAME: &str = "mapreduce";
+static STATE_COLL: &str = "state";
+static STATE_RECORD: &str = "current_master_state";
 static MAP_TASKS_COLL: &str = "map_tasks";
 static REDUCE_TASKS_COLL: &str = "reduce_tasks";
 static MONGO_HOST: &str = "mongodb://localhost:27017";
+static POLL_INTERVAL: Duration = Duration::from_secs(1);
+// Events kept for clients watching a job that fall behind, before they start missing some
+static EVENT_BACKLOG: usize = 1024;
+// How long workers that cannot be given a task are told to wait before asking again
+static WAIT_INTERVAL: Duration = Duration::from_secs(1);
+// Workers that have not sent a heartbeat for this long are considered dead
+static HEARTBEAT_TIMEOUT: Duration = Duration::from_secs(10);
 
-#[derive(Debug, Default)]
-pub struct TaskService {}
+// Response telling a worker to shut down, either because the job is complete or because it was
+// aborted
+fn exit_response(completed: bool, reason: String) -> TaskResponse {
+    action_response(Action::Exit(Exit {
+        completed,
+        reason,
+        drained: false,
+    }))
+}
 
-#[tonic::async_trait]
-impl Task for TaskService {
-    async fn send_task(
-        &self,
-        request: Request<TaskRequest>,
-    ) -> Result<Response<TaskResponse>, Status> {
-        println!(
-            "DEBUG: Master got a request from worker {}",
-            request.get_ref().id
-        );
+fn action_response(action: Action) -> TaskResponse {
+    TaskResponse {
+        action: Some(action),
+        finished_jobs: Vec::new(),
+    }
+}
 
-        // Initialize client handler
-        let client_options = ClientOptions::parse(MONGO_HOST)
-            .await
-            .unwrap_or_else(|err| {
-                eprintln!("ERROR: could not parse address: {err}");
-                exit(1)
-            });
-        let client = Client::with_options(client_options).unwrap_or_else(|err| {
-            eprintln!("ERROR: could not initialize client: {err}");
+// Directory the master was started in, which job inputs are relative to
+fn master_dir() -> PathBuf {
+    env::current_dir().unwrap_or_else(|_| PathBuf::from("."))
+}
+
+// Whether a path a job was submitted with stays within the master's working directory, which
+// submitted jobs may not read or write outside of
+fn within_master_dir(path: &str) -> bool {
+    Path::new(path)
+        .components()
+        .all(|component| matches!(component, Component::Normal(_) | Component::CurDir))
+}
+
+// Directory a submitted job writes its output to: the one it was submitted with, which must be
+// within the master's working directory `dir`, or else one named after the job
+fn submitted_output_dir(dir: &Path, output_dir: &str, job_id: &str) -> Result<PathBuf, String> {
+    if output_dir.is_empty() {
+        return Ok(dir.join(job_id));
+    }
+    if !within_master_dir(output_dir) {
+        return Err(format!(
+            "invalid output directory {output_dir}, expected a relative path without .."
+        ));
+    }
+    Ok(dir.join(output_dir))
+}
+
+fn not_registered(id: u64) -> Status {
+    Status::failed_precondition(format!("Worker {id} is not registered."))
+}
+
+fn no_such_job(job_id: &str) -> Status {
+    Status::not_found(format!("No job with id {job_id} is running."))
+}
+
+impl From<PhaseProgress> for PhaseStatus {
+    fn from(progress: PhaseProgress) -> PhaseStatus {
+        PhaseStatus {
+            idle: progress.idle,
+            in_progress: progress.in_progress,
+            completed: progress.completed,
+        }
+    }
+}
+
+impl From<TaskState> for admin::TaskState {
+    fn from(state: TaskState) -> admin::TaskState {
+        match state {
+            TaskState::Idle => admin::TaskState::Idle,
+            TaskState::InProgress => admin::TaskState::InProgress,
+            TaskState::Completed => admin::TaskState::Completed,
+        }
+    }
+}
+
+fn tasks_coll(is_map: bool) -> &'static str {
+    if is_map {
+        MAP_TASKS_COLL
+    } else {
+        REDUCE_TASKS_COLL
+    }
+}
+
+fn phase_name(is_map: bool) -> &'static str {
+    if is_map {
+        "map"
+    } else {
+        "reduce"
+    }
+}
+
+// Name of the counter in the state record of the phase's tasks in some state
+fn counter(is_map: bool, state: &str) -> String {
+    format!("{}_{}", phase_name(is_map), state)
+}
+
+// Reads the progress counters of a phase from the state record. Maintained as tasks change
+// state, so that progress can be read without scanning the tasks.
+async fn phase_progress(client: &Client, db_name: &str, is_map: bool) -> PhaseProgress {
+    let state = mongo_utils::get_record(client, db_name, STATE_COLL, STATE_RECORD)
+        .await
+        .unwrap_or_default();
+    let count = |state_name: &str| state.get_i64(counter(is_map, state_name)).unwrap_or(0);
+    PhaseProgress {
+        idle: count("idle"),
+        in_progress: count("in_progress"),
+        completed: count("completed"),
+    }
+}
+
+// Resets the progress counters of both phases from the task records. Counters can drift if the
+// master crashes between updating a task and updating the counters, so this is done whenever a
+// job is started or resumed.
+async fn recount_progress(client: &Client, db_name: &str) {
+    for is_map in [true, false] {
+        let coll_name = tasks_coll(is_map);
+        let idle = doc! {"is_assigned": false, "done": false};
+        let in_progress = doc! {"is_assigned": true, "done": false};
+        let completed = doc! {"done": true};
+        for (state_name, filter) in [
+            ("idle", idle),
+            ("in_progress", in_progress),
+            ("completed", completed),
+        ] {
+            let count = mongo_utils::count_tasks(client, db_name, coll_name, filter).await;
+            mongo_utils::update_count(
+                client,
+                db_name,
+                STATE_COLL,
+                STATE_RECORD,
+                &counter(is_map, state_name),
+                count as i64,
+            )
+            .await;
+        }
+    }
+    let map_tasks_left = phase_progress(client, db_name, true).await.remaining();
+    mongo_utils::update_count(
+        client,
+        db_name,
+        STATE_COLL,
+        STATE_RECORD,
+        "map_tasks_left",
+        map_tasks_left,
+    )
+    .await;
+}
+
+// Initializes a database client handler
+async fn connect() -> Client {
+    let client_options = ClientOptions::parse(MONGO_HOST)
+        .await
+        .unwrap_or_else(|err| {
+            eprintln!("ERROR: could not parse address: {err}");
             exit(1)
         });
-        let db = client.database(DB_NAME);
+    Client::with_options(client_options).unwrap_or_else(|err| {
+        eprintln!("ERROR: could not initialize client: {err}");
+        exit(1)
+    })
+}
+
+// State of one of the jobs the master is running, and the handling of its tasks
+#[derive(Clone, Debug)]
+pub struct JobService {
+    // Long-lived handle to the store, shared by every request
+    client: Client,
+    job_id: String,
+    // Name the job was submitted under, for display only
+    name: String,
+    n_map: i64,
+    n_reduce: i64,
+    // Directory the job's inputs are relative to: the master's working directory
+    dir: PathBuf,
+    // Directory the job's output files are written to, the master's working directory unless
+    // the job was submitted with another
+    output_dir: PathBuf,
+    // Token the job's reduce tasks present to the workers serving its map output
+    shuffle_token: String,
+    // Database holding the state of the job
+    db_name: String,
+    // Workers serving the master, shared by every job
+    workers: Arc<Mutex<WorkerRegistry>>,
+    options: JobOptions,
+    // Set once a task has run out of retries or the job was cancelled, describing why the job
+    // was aborted
+    abort_reason: Arc<Mutex<Option<String>>>,
+    // Whether the job was aborted because it was cancelled
+    cancelled: Arc<Mutex<bool>>,
+    // Tasks whose output is being moved into place, and the worker whose attempt is committing
+    committing: Arc<Mutex<HashMap<TaskRef, u64>>>,
+    // Task states, loaded from the store at startup. Changes are made here first and then
+    // written through to the store.
+    scheduler: Arc<Mutex<Scheduler>>,
+    // Workers holding the output of completed map tasks
+    map_outputs: Arc<Mutex<MapOutputs>>,
+    // Clients watching the job
+    events: broadcast::Sender<JobEvent>,
+    // Phase and state last announced to them
+    announced: Arc<Mutex<(Option<bool>, JobState)>>,
+}
+impl JobService {
+    pub fn new(
+        client: Client,
+        workers: Arc<Mutex<WorkerRegistry>>,
+        job_id: &str,
+        options: JobOptions,
+    ) -> JobService {
+        let dir = master_dir();
+        JobService {
+            client,
+            job_id: job_id.to_string(),
+            name: String::new(),
+            n_map: 0,
+            n_reduce: 0,
+            output_dir: dir.clone(),
+            dir,
+            shuffle_token: String::new(),
+            db_name: mongo_utils::job_db_name(job_id),
+            workers,
+            options,
+            abort_reason: Arc::new(Mutex::new(None)),
+            cancelled: Arc::new(Mutex::new(false)),
+            committing: Arc::new(Mutex::new(HashMap::new())),
+            scheduler: Arc::new(Mutex::new(Scheduler::new())),
+            map_outputs: Arc::new(Mutex::new(MapOutputs::new())),
+            events: broadcast::channel(EVENT_BACKLOG).0,
+            announced: Arc::new(Mutex::new((None, JobState::Running))),
+        }
+    }
+
+    // Loads the job's parameters, the state of every task and where completed map tasks' output
+    // lives from the store
+    pub async fn load(&mut self) {
+        let client = &self.client;
+        let get_param =
+            |name| mongo_utils::get_val(client, &self.db_name, STATE_COLL, STATE_RECORD, name);
+        self.n_map = get_param("n_map").await.unwrap();
+        self.n_reduce = get_param("n_reduce").await.unwrap();
+        let state = mongo_utils::get_record(client, &self.db_name, STATE_COLL, STATE_RECORD)
+            .await
+            .unwrap_or_default();
+        self.name = state.get_str("job_name").unwrap_or_default().to_string();
+        if let Ok(output_dir) = state.get_str("output_dir") {
+            self.output_dir = PathBuf::from(output_dir);
+        }
+        // Jobs recorded by masters that did not give each job a shuffle token get one now
+        self.shuffle_token = match state.get_str("shuffle_token") {
+            Ok(token) => token.to_string(),
+            Err(_) => new_shuffle_token(),
+        };
+
+        // Completed map tasks whose output has no known location
+        let mut unlocated = Vec::new();
+        for is_map in [true, false] {
+            let tasks = mongo_utils::get_tasks(client, &self.db_name, tasks_coll(is_map)).await;
+            let mut scheduler = self.scheduler.lock().unwrap();
+            for task in tasks {
+                let state = if task.get_bool("done").unwrap() {
+                    TaskState::Completed
+                } else if task.get_bool("is_assigned").unwrap() {
+                    TaskState::InProgress
+                } else {
+                    TaskState::Idle
+                };
+                let task_ref = TaskRef {
+                    name: task.get_str("name").unwrap().to_string(),
+                    is_map,
+                };
+                let tasknum = task.get_i32("tasknum").unwrap();
+                scheduler.add(task_ref, tasknum, state);
+
+                let completed = state == TaskState::Completed;
+                if let (true, true, Ok(addr)) = (is_map, completed, task.get_str("output_addr")) {
+                    let output = MapOutput {
+                        worker_id: task.get_i64("worker_id").unwrap_or(0) as u64,
+                        addr: addr.to_string(),
+                    };
+                    self.map_outputs
+                        .lock()
+                        .unwrap()
+                        .insert(tasknum as i64, output);
+                } else if is_map && completed {
+                    unlocated.push(tasknum as i64);
+                }
+            }
+        }
+        let reason = "it was committed before workers served map output";
+        self.rerun_maps(client, &unlocated, reason).await;
+
+        // Output recorded by a previous master is held by workers this one does not know, which
+        // have most likely exited with it. Ids start over with every master, so a worker is only
+        // trusted with output if its address matches too.
+        let lost = {
+            let workers = self.workers.lock().unwrap();
+            self.map_outputs.lock().unwrap().retain_held(|output| {
+                workers
+                    .get(output.worker_id)
+                    .is_some_and(|worker| worker.shuffle_addr == output.addr)
+            })
+        };
+        let reason = "its worker is not registered with this master";
+        self.rerun_maps(client, &lost, reason).await;
+    }
+
+    // Returns true once every map and reduce task has been completed
+    pub fn job_done(&self) -> bool {
+        self.scheduler.lock().unwrap().done()
+    }
+
+    pub fn abort_reason(&self) -> Option<String> {
+        self.abort_reason.lock().unwrap().clone()
+    }
+
+    pub fn job_state(&self) -> JobState {
+        if *self.cancelled.lock().unwrap() {
+            JobState::Cancelled
+        } else if self.abort_reason().is_some() {
+            JobState::Aborted
+        } else if self.job_done() {
+            JobState::Completed
+        } else {
+            JobState::Running
+        }
+    }
+
+    fn abort(&self, reason: String) {
+        {
+            let mut abort_reason = self.abort_reason.lock().unwrap();
+            if abort_reason.is_some() {
+                return;
+            }
+            eprintln!("ERROR: Aborting job: {reason}");
+            *abort_reason = Some(reason);
+        }
+        self.publish(None);
+    }
+
+    // Cancels the job on an operator's request, for good: it is marked cancelled in the store so
+    // that it cannot be resumed, and the output its tasks committed is removed. Workers running
+    // its tasks are told to abort them when they next send a heartbeat. Returns false if the job
+    // had already finished.
+    async fn cancel(&self) -> bool {
+        if !self.mark_cancelled() {
+            return false;
+        }
+        mongo_utils::update_bool(
+            &self.client,
+            &self.db_name,
+            STATE_COLL,
+            STATE_RECORD,
+            "cancelled",
+            true,
+        )
+        .await;
+        self.remove_output();
+        true
+    }
+
+    // Stops handing out and committing the job's tasks, unless it has already finished
+    fn mark_cancelled(&self) -> bool {
+        {
+            let mut abort_reason = self.abort_reason.lock().unwrap();
+            if abort_reason.is_some() || self.job_done() {
+                return false;
+            }
+            *self.cancelled.lock().unwrap() = true;
+            *abort_reason = Some("the job was cancelled".to_string());
+        }
+        self.publish(None);
+        true
+    }
+
+    // Removes the files committed attempts of the job's tasks left in its output directory, and
+    // the directory itself if the job was given one of its own and nothing else is left in it.
+    // Files are removed by name, which is safe as no two jobs share an output directory.
+    // Attempts still running clean up after themselves.
+    fn remove_output(&self) {
+        for tasknum in 0..self.n_map {
+            let _ = fs::remove_file(job::skipped_filename(&self.output_dir, true, tasknum));
+        }
+        for tasknum in 0..self.n_reduce {
+            let _ = fs::remove_file(job::output_filename(&self.output_dir, tasknum));
+            let _ = fs::remove_file(job::skipped_filename(&self.output_dir, false, tasknum));
+        }
+        if self.output_dir != self.dir {
+            let _ = fs::remove_dir(&self.output_dir);
+        }
+    }
+
+    // Returns true until the job is complete, aborted or cancelled
+    pub fn running(&self) -> bool {
+        self.job_state() == JobState::Running
+    }
+
+    fn job_task(&self, task: &TaskRef) -> JobTask {
+        JobTask {
+            job_id: self.job_id.clone(),
+            task: task.clone(),
+        }
+    }
+
+    // Wraps an event with the progress of each phase
+    fn job_event(&self, event: Event) -> JobEvent {
+        let scheduler = self.scheduler.lock().unwrap();
+        JobEvent {
+            event: Some(event),
+            map: Some(scheduler.progress(true).into()),
+            reduce: Some(scheduler.progress(false).into()),
+        }
+    }
+
+    fn finished_event(&self, state: JobState) -> Event {
+        Event::JobFinished(JobFinished {
+            state: state as i32,
+            reason: self.abort_reason().unwrap_or_default(),
+        })
+    }
+
+    // Tells the clients watching the job about an event, followed by any change of phase or
+    // state since they were last told
+    fn publish(&self, event: Option<Event>) {
+        let state = self.job_state();
+        let phase = self.scheduler.lock().unwrap().current_phase();
+        // Held while sending, so that every client sees the events in the same order
+        let mut announced = self.announced.lock().unwrap();
+        let mut events: Vec<Event> = event.into_iter().collect();
+        if let Some(is_map) = phase.filter(|_| state == JobState::Running && phase != announced.0) {
+            events.push(Event::PhaseChanged(PhaseChanged { is_map }));
+            announced.0 = phase;
+        }
+        if state != JobState::Running && announced.1 == JobState::Running {
+            events.push(self.finished_event(state));
+            announced.1 = state;
+        }
+        for event in events {
+            // Nobody may be watching
+            let _ = self.events.send(self.job_event(event));
+        }
+    }
+
+    // Subscribes to the job's events, returning the events a new client starts with: the
+    // current phase, or the job's outcome if it has finished
+    fn watch(&self) -> (broadcast::Receiver<JobEvent>, Vec<JobEvent>) {
+        self.publish(None);
+        // Subscribing while the announced phase and state cannot change, the client gets each
+        // change either as an opening event or from the channel, but not both
+        let announced = self.announced.lock().unwrap();
+        let receiver = self.events.subscribe();
+        let opening = match *announced {
+            (phase, JobState::Running) => {
+                phase.map(|is_map| Event::PhaseChanged(PhaseChanged { is_map }))
+            }
+            (_, state) => Some(self.finished_event(state)),
+        };
+        let opening = opening.map(|event| self.job_event(event));
+        (receiver, opening.into_iter().collect())
+    }
+
+    fn job_summary(&self) -> JobSummary {
+        let state = self.job_state();
+        let scheduler = self.scheduler.lock().unwrap();
+        JobSummary {
+            job_id: self.job_id.clone(),
+            name: self.name.clone(),
+            state: state as i32,
+            map: Some(scheduler.progress(true).into()),
+            reduce: Some(scheduler.progress(false).into()),
+        }
+    }
+
+    // Status of the job and of each of its tasks, and of the workers serving the master.
+    // Attempts and errors are read from the store, everything else from memory.
+    async fn job_status(&self) -> JobStatus {
+        let summary = self.job_summary();
+        let mut tasks = Vec::new();
+        for is_map in [true, false] {
+            let records =
+                mongo_utils::get_tasks(&self.client, &self.db_name, tasks_coll(is_map)).await;
+            for record in records {
+                let task = TaskRef {
+                    name: record.get_str("name").unwrap_or_default().to_string(),
+                    is_map,
+                };
+                let Some(state) = self.scheduler.lock().unwrap().state(&task) else {
+                    continue;
+                };
+                let mut worker_ids = self.workers.lock().unwrap().running(&self.job_task(&task));
+                worker_ids.sort();
+                tasks.push(TaskStatus {
+                    name: task.name,
+                    is_map,
+                    state: admin::TaskState::from(state) as i32,
+                    attempts: record.get_i64("attempts").unwrap_or(0),
+                    worker_ids,
+                    last_error: record.get_str("last_error").unwrap_or_default().to_string(),
+                });
+            }
+        }
+        // Map tasks first, reduce tasks in task num order
+        tasks.sort_by_key(|task| {
+            (
+                !task.is_map,
+                task.name.parse::<i64>().ok(),
+                task.name.clone(),
+            )
+        });
+
+        let now = Instant::now();
+        let mut workers: Vec<WorkerStatus> = self
+            .workers
+            .lock()
+            .unwrap()
+            .workers()
+            .map(|(id, worker)| WorkerStatus {
+                worker_id: *id,
+                pid: worker.pid,
+                hostname: worker.hostname.clone(),
+                shuffle_addr: worker.shuffle_addr.clone(),
+                job_id: worker
+                    .current_task
+                    .as_ref()
+                    .map(|running| running.job_id.clone())
+                    .unwrap_or_default(),
+                task_name: worker
+                    .current_task
+                    .as_ref()
+                    .map(|running| running.task.name.clone())
+                    .unwrap_or_default(),
+                is_map: worker
+                    .current_task
+                    .as_ref()
+                    .is_some_and(|running| running.task.is_map),
+                tasks_completed: worker.tasks_completed,
+                last_seen_ms: now.duration_since(worker.last_seen).as_millis() as u64,
+                draining: worker.draining,
+            })
+            .collect();
+        workers.sort_by_key(|worker| worker.worker_id);
+
+        JobStatus {
+            job_id: summary.job_id,
+            name: summary.name,
+            state: summary.state,
+            reason: self.abort_reason().unwrap_or_default(),
+            map: summary.map,
+            reduce: summary.reduce,
+            tasks,
+            workers,
+        }
+    }
+
+    // Moves a task between the progress counters of its phase
+    async fn count_transition(&self, client: &Client, is_map: bool, from: &str, to: &str) {
+        let (from, to) = (counter(is_map, from), counter(is_map, to));
+        let mut amounts = vec![(from.as_str(), -1), (to.as_str(), 1)];
+        if is_map && to == counter(true, "completed") {
+            amounts.push(("map_tasks_left", -1));
+        } else if is_map && from == counter(true, "completed") {
+            amounts.push(("map_tasks_left", 1));
+        }
+        mongo_utils::increment_counts(client, &self.db_name, STATE_COLL, STATE_RECORD, &amounts)
+            .await;
+    }
+
+    // Records that the scheduler handed a task out to a worker, both in the registry and in the
+    // task's record
+    async fn assign(&self, client: &Client, id: u64, task: &TaskRef) {
+        let coll_name = tasks_coll(task.is_map);
+        let idle = doc! {"is_assigned": false, "done": false};
+        let assigned = doc! {"is_assigned": true, "worker_id": id as i64};
+        if mongo_utils::transition_task(
+            client,
+            &self.db_name,
+            coll_name,
+            &task.name,
+            idle,
+            assigned,
+        )
+        .await
+        .is_some()
+        {
+            self.count_transition(client, task.is_map, "idle", "in_progress")
+                .await;
+        } else {
+            eprintln!("WARN: Task {} was not idle in the store.", task.name);
+        }
+        let attempt = mongo_utils::increment_count(
+            client,
+            &self.db_name,
+            coll_name,
+            &task.name,
+            "attempts",
+            1,
+        )
+        .await;
+
+        self.workers
+            .lock()
+            .unwrap()
+            .assign(id, self.job_task(task), Instant::now());
+        println!(
+            "INFO: Assigned task {} to worker {} (attempt {}).",
+            task.name, id, attempt
+        );
+        self.publish(Some(Event::TaskAssigned(TaskAssigned {
+            name: task.name.clone(),
+            is_map: task.is_map,
+            worker_id: id,
+            attempt,
+            backup: false,
+        })));
+    }
 
-        // Get existing map tasks from database
-        let coll = db.collection::<mongodb::bson::Document>(MAP_TASKS_COLL);
+    // Returns an in-progress task to the idle pool
+    async fn mark_idle(&self, client: &Client, task: &TaskRef, reason: &str) {
+        if !self.scheduler.lock().unwrap().requeue(task) {
+            return;
+        }
+        self.publish(Some(Event::TaskReassigned(TaskReassigned {
+            name: task.name.clone(),
+            is_map: task.is_map,
+            reason: reason.to_string(),
+        })));
+        let in_progress = doc! {"is_assigned": true, "done": false};
+        let idle = doc! {"is_assigned": false};
+        let coll_name = tasks_coll(task.is_map);
+        if mongo_utils::transition_task(
+            client,
+            &self.db_name,
+            coll_name,
+            &task.name,
+            in_progress,
+            idle,
+        )
+        .await
+        .is_some()
+        {
+            self.count_transition(client, task.is_map, "in_progress", "idle")
+                .await;
+        }
+    }
 
-        // Loop over all tasks looking for an idle one to assign
-        let distinct = coll.distinct("name", None, None).await;
+    // Marks a task done once its output has been committed, recording which worker holds the
+    // output of map tasks
+    async fn mark_done(&self, client: &Client, task: &TaskRef, output: Option<MapOutput>) {
+        let tasknum = {
+            let mut scheduler = self.scheduler.lock().unwrap();
+            if scheduler.complete(task).is_none() {
+                return;
+            }
+            scheduler.tasknum(task).unwrap()
+        };
+        let not_done = doc! {"done": false};
+        let mut done = doc! {"done": true};
+        if let Some(output) = output {
+            done.insert("worker_id", output.worker_id as i64);
+            done.insert("output_addr", output.addr.clone());
+            self.map_outputs
+                .lock()
+                .unwrap()
+                .insert(tasknum as i64, output);
+        }
+        let coll_name = tasks_coll(task.is_map);
+        let Some(before) = mongo_utils::transition_task(
+            client,
+            &self.db_name,
+            coll_name,
+            &task.name,
+            not_done,
+            done,
+        )
+        .await
+        else {
+            return;
+        };
+        // A task can be committed after it was requeued, by a worker wrongly thought to be dead
+        let from = if before.get_bool("is_assigned").unwrap_or(false) {
+            "in_progress"
+        } else {
+            "idle"
+        };
+        self.count_transition(client, task.is_map, from, "completed")
+            .await;
+    }
 
-        let mut map_phase_done = true;
-        for key in distinct.unwrap() {
-            let res =
-                mongo_utils::get_task(&client, DB_NAME, MAP_TASKS_COLL, key.as_str().unwrap())
+    // Returns completed map tasks whose output was lost to the idle pool, so that they run again
+    // before any more reduce tasks are handed out. Reduce tasks that still need the output get
+    // its new location when they are next handed out.
+    async fn rerun_maps(&self, client: &Client, map_tasknums: &[i64], reason: &str) {
+        // Nothing reads map output once every reduce task is complete
+        if self.scheduler.lock().unwrap().progress(false).done() {
+            return;
+        }
+        for map_tasknum in map_tasknums {
+            let task = {
+                let mut scheduler = self.scheduler.lock().unwrap();
+                match scheduler.find(true, *map_tasknum as i32) {
+                    Some(task) if scheduler.reset(&task) => task,
+                    _ => continue,
+                }
+            };
+            println!(
+                "INFO: Output of map task {} was lost ({}), running it again.",
+                task.name, reason
+            );
+            self.publish(Some(Event::TaskReassigned(TaskReassigned {
+                name: task.name.clone(),
+                is_map: true,
+                reason: format!("its output was lost ({reason})"),
+            })));
+            let done = doc! {"done": true};
+            let idle = doc! {"done": false, "is_assigned": false};
+            if mongo_utils::transition_task(
+                client,
+                &self.db_name,
+                MAP_TASKS_COLL,
+                &task.name,
+                done,
+                idle,
+            )
+            .await
+            .is_some()
+            {
+                self.count_transition(client, true, "completed", "idle")
                     .await;
+            }
+            // The task did not fail, so running it again does not count against its attempts
+            mongo_utils::increment_count(
+                client,
+                &self.db_name,
+                MAP_TASKS_COLL,
+                &task.name,
+                "attempts",
+                -1,
+            )
+            .await;
+        }
+    }
 
-            // If a single map task is not done, set a flag to indicate map phase unfinished
-            if !(res.4.unwrap()) {
-                map_phase_done = false;
-            }
-            // If not assigned, hand out this task
-            if !(res.1.unwrap()) {
-                let response_filename = &res.0.unwrap();
-                let tasknum = res.3.unwrap();
-                let reply = TaskResponse {
-                    task_name: response_filename.to_string(),
-                    is_assigned: false,
-                    is_map: true,
-                    tasknum,
-                    done: false,
+    // Handles a reduce task that could not fetch the map output at `location`: the map task is
+    // run again if its output was still expected there, and the reduce task is retried like any
+    // other failed task, after the map task has completed again. Counting fetch failures against
+    // the reduce task's attempts keeps map output that can never be fetched, e.g. because
+    // workers advertise an address other workers cannot reach, from being produced forever.
+    async fn fetch_failed(
+        &self,
+        client: &Client,
+        task: &TaskRef,
+        worker_id: u64,
+        location: &str,
+        error: &str,
+    ) {
+        if let Ok(Location::Shuffle {
+            addr, map_tasknum, ..
+        }) = location.parse::<Location>()
+        {
+            let lost = self
+                .map_outputs
+                .lock()
+                .unwrap()
+                .remove_at(map_tasknum, &addr);
+            if lost {
+                let reason = format!("could not be fetched from {addr}");
+                self.rerun_maps(client, &[map_tasknum], &reason).await;
+            }
+        }
+        self.fail(client, task, worker_id, false, None, error).await;
+    }
+
+    // Builds the response handing a task to a worker. Returns None for a reduce task if the
+    // output of a map task has no known location, i.e. it was lost and the map task has to run
+    // again first.
+    async fn task_response(
+        &self,
+        client: &Client,
+        name: &str,
+        is_map: bool,
+        tasknum: i32,
+    ) -> Option<TaskResponse> {
+        let job = Some(JobInfo {
+            job_id: self.job_id.clone(),
+            n_map: self.n_map,
+            n_reduce: self.n_reduce,
+            output_dir: self.output_dir.to_string_lossy().into_owned(),
+            shuffle_token: self.shuffle_token.clone(),
+            options: Some(tasks::JobOptions {
+                max_attempts: self.options.max_attempts,
+                max_user_failures: self.options.max_user_failures,
+                skip_bad_records: self.options.skip_bad_records,
+                backup_threshold: self.options.backup_threshold,
+            }),
+        });
+        let skip_records = self.skip_records(client, name, is_map).await;
+
+        let action = if is_map {
+            Action::MapTask(MapTask {
+                task_name: name.to_string(),
+                tasknum,
+                input: self.dir.join(name).to_string_lossy().into_owned(),
+                job,
+                skip_records,
+            })
+        } else {
+            // Reduce tasks are stored with a no-op task num, their name is their task num
+            let tasknum = i64::from_str(name).unwrap();
+            let map_outputs = self.map_outputs.lock().unwrap();
+            let intermediate_locations = (0..self.n_map)
+                .map(|map_tasknum| {
+                    map_outputs
+                        .location(map_tasknum, tasknum)
+                        .map(|location| location.to_string())
+                })
+                .collect::<Option<Vec<String>>>()?;
+            Action::ReduceTask(ReduceTask {
+                task_name: name.to_string(),
+                tasknum: tasknum as i32,
+                intermediate_locations,
+                job,
+                skip_records,
+            })
+        };
+        Some(action_response(action))
+    }
+
+    // Hands an idle worker a backup attempt of one of a nearly finished phase's `in_progress`
+    // tasks that is only being run by a single other worker. Whichever attempt commits first wins.
+    async fn backup(
+        &self,
+        client: &Client,
+        worker_id: u64,
+        is_map: bool,
+        in_progress: &[(String, i32)],
+    ) -> Option<TaskResponse> {
+        let (name, tasknum) = {
+            let mut workers = self.workers.lock().unwrap();
+            let committing = self.committing.lock().unwrap();
+            let (name, tasknum) = in_progress.iter().find(|(name, _)| {
+                let task = TaskRef {
+                    name: name.clone(),
+                    is_map,
                 };
+                let running = workers.running(&self.job_task(&task));
+                !committing.contains_key(&task) && running.len() == 1 && running[0] != worker_id
+            })?;
+            let task = TaskRef {
+                name: name.clone(),
+                is_map,
+            };
+            workers.assign(worker_id, self.job_task(&task), Instant::now());
+            (name.clone(), *tasknum)
+        };
+        let Some(reply) = self.task_response(client, &name, is_map, tasknum).await else {
+            self.workers
+                .lock()
+                .unwrap()
+                .finish(worker_id, Instant::now());
+            return None;
+        };
+        // Backups are not counted as attempts of their own, and race the attempt they back up
+        let attempt =
+            mongo_utils::get_val(client, &self.db_name, tasks_coll(is_map), &name, "attempts")
+                .await
+                .unwrap_or_default();
 
-                update_assigned(&client, DB_NAME, MAP_TASKS_COLL, response_filename, true).await;
-                return Ok(Response::new(reply));
+        println!(
+            "INFO: Assigned backup of task {} to worker {} (attempt {}).",
+            name, worker_id, attempt
+        );
+        self.publish(Some(Event::TaskAssigned(TaskAssigned {
+            name: name.clone(),
+            is_map,
+            worker_id,
+            attempt,
+            backup: true,
+        })));
+        Some(reply)
+    }
+
+    // Returns a failed task to the idle pool, unless another attempt of it is still running
+    async fn requeue(&self, client: &Client, task: &TaskRef, reason: &str) {
+        if self
+            .workers
+            .lock()
+            .unwrap()
+            .running(&self.job_task(task))
+            .is_empty()
+        {
+            self.mark_idle(client, task, reason).await;
+        } else {
+            println!(
+                "INFO: Another attempt of task {} is still running, not retrying it yet.",
+                task.name
+            );
+        }
+    }
+
+    // Forgets a commit in progress if it belongs to the given worker
+    fn release_commit(&self, task: &TaskRef, worker_id: u64) {
+        let mut committing = self.committing.lock().unwrap();
+        if committing.get(task) == Some(&worker_id) {
+            committing.remove(task);
+        }
+    }
+
+    // Offsets of the records a task should skip, in skip mode
+    async fn skip_records(&self, client: &Client, name: &str, is_map: bool) -> Vec<i64> {
+        if !self.options.skip_bad_records {
+            return Vec::new();
+        }
+        mongo_utils::get_bad_records(
+            client,
+            &self.db_name,
+            tasks_coll(is_map),
+            name,
+            JobOptions::SKIP_AFTER_FAILURES,
+        )
+        .await
+    }
+
+    // Records a failed attempt of a task, returning it to the idle pool to be retried, or
+    // aborting the job if it has run out of attempts. In skip mode, a failure pinned to a bad
+    // record does not count against the task's retries, since the record will eventually be
+    // skipped.
+    async fn fail(
+        &self,
+        client: &Client,
+        task: &TaskRef,
+        worker_id: u64,
+        user_code: bool,
+        bad_record: Option<i64>,
+        error: &str,
+    ) {
+        self.publish(Some(Event::TaskFailed(TaskFailed {
+            name: task.name.clone(),
+            is_map: task.is_map,
+            worker_id,
+            error: error.to_string(),
+        })));
+        let coll_name = tasks_coll(task.is_map);
+        mongo_utils::update_last_error(client, &self.db_name, coll_name, &task.name, error).await;
+
+        if let Some(offset) = bad_record.filter(|_| self.options.skip_bad_records) {
+            let failures = mongo_utils::increment_record_failures(
+                client,
+                &self.db_name,
+                coll_name,
+                &task.name,
+                offset,
+            )
+            .await;
+            mongo_utils::increment_count(
+                client,
+                &self.db_name,
+                coll_name,
+                &task.name,
+                "attempts",
+                -1,
+            )
+            .await;
+            if failures >= JobOptions::SKIP_AFTER_FAILURES {
+                println!(
+                    "INFO: Record {} of task {} failed {} times, skipping it on retry.",
+                    offset, task.name, failures
+                );
+            } else {
+                println!(
+                    "INFO: Record {} of task {} failed, retrying: {}",
+                    offset, task.name, error
+                );
             }
+            self.requeue(client, task, error).await;
+            return;
         }
 
-        if !map_phase_done {
-            return Err(Status::not_found(
-                "Reduce tasks avaiable but map phase still pending.",
+        let user_failures = if user_code {
+            mongo_utils::increment_count(
+                client,
+                &self.db_name,
+                coll_name,
+                &task.name,
+                "user_failures",
+                1,
+            )
+            .await
+        } else {
+            mongo_utils::get_val(
+                client,
+                &self.db_name,
+                coll_name,
+                &task.name,
+                "user_failures",
+            )
+            .await
+            .unwrap_or(0)
+        };
+        let attempts =
+            mongo_utils::get_val(client, &self.db_name, coll_name, &task.name, "attempts")
+                .await
+                .unwrap_or(0);
+
+        if self.options.exhausted(attempts, user_failures) {
+            self.abort(format!(
+                "task {} failed after {} attempts ({} in the user {} function), last error: {}",
+                task.name,
+                attempts,
+                user_failures,
+                if task.is_map { "map" } else { "reduce" },
+                error
             ));
         } else {
-            let coll = db.collection::<mongodb::bson::Document>(REDUCE_TASKS_COLL);
-            let distinct = coll.distinct("name", None, None).await;
-
-            for key in distinct.unwrap() {
-                let res = mongo_utils::get_task(
-                    &client,
-                    DB_NAME,
-                    REDUCE_TASKS_COLL,
-                    key.as_str().unwrap(),
+            println!(
+                "INFO: Task {} failed on attempt {}: {}",
+                task.name, attempts, error
+            );
+            self.requeue(client, task, error).await;
+        }
+    }
+
+    // Hands a worker the next idle task of the job's current phase, or a backup attempt of a
+    // running task if the phase is nearly finished. Returns None if the job has no task for it.
+    async fn next_task(&self, client: &Client, worker_id: u64) -> Option<TaskResponse> {
+        let next = self.scheduler.lock().unwrap().next_idle();
+        if let Some((task, tasknum)) = next {
+            let reply = self
+                .task_response(client, &task.name, task.is_map, tasknum)
+                .await;
+            let Some(reply) = reply else {
+                // Map output was lost since the reduce phase started, and the map task that
+                // produced it runs again before any more reduce tasks
+                self.scheduler.lock().unwrap().requeue(&task);
+                return None;
+            };
+            self.assign(client, worker_id, &task).await;
+            return Some(reply);
+        }
+
+        let (is_map, progress, in_progress) = {
+            let scheduler = self.scheduler.lock().unwrap();
+            let is_map = scheduler.current_phase()?;
+            (
+                is_map,
+                scheduler.progress(is_map),
+                scheduler.in_progress(is_map),
+            )
+        };
+        if !self
+            .options
+            .wants_backups(progress.remaining() as u64, progress.total() as u64)
+        {
+            return None;
+        }
+        self.backup(client, worker_id, is_map, &in_progress).await
+    }
+
+    // Handles a worker's report of how an attempt of one of the job's tasks ended, returning
+    // whether a successful attempt may commit its output. `shuffle_addr` is where the worker
+    // serves its map output.
+    async fn report(&self, client: &Client, report: &TaskReport, shuffle_addr: String) -> bool {
+        let task = TaskRef {
+            name: report.task_name.clone(),
+            is_map: report.is_map,
+        };
+        let outcome = TaskOutcome::from_i32(report.outcome);
+
+        // Attempts still running when the job finished are discarded. An attempt of a cancelled
+        // job may have committed its output after the job's output was removed.
+        if !self.running() {
+            self.workers
+                .lock()
+                .unwrap()
+                .finish(report.worker_id, Instant::now());
+            self.release_commit(&task, report.worker_id);
+            if outcome == Some(TaskOutcome::Committed) && self.job_state() == JobState::Cancelled {
+                self.remove_output();
+            }
+            return false;
+        }
+
+        // Only the first successful attempt of a task may move its output into place. The task
+        // is done once that attempt confirms the commit.
+        if outcome == Some(TaskOutcome::Succeeded) {
+            let state = self.scheduler.lock().unwrap().state(&task);
+            let commit = {
+                let mut committing = self.committing.lock().unwrap();
+                let done = state.is_none_or(|state| state == TaskState::Completed);
+                if !done && !committing.contains_key(&task) {
+                    committing.insert(task.clone(), report.worker_id);
+                    true
+                } else {
+                    false
+                }
+            };
+            if commit {
+                println!(
+                    "INFO: Worker {} is committing task {}.",
+                    report.worker_id, task.name
+                );
+            } else {
+                println!(
+                    "INFO: Discarding output of worker {} for task {}, another attempt committed first.",
+                    report.worker_id, task.name
+                );
+                self.workers
+                    .lock()
+                    .unwrap()
+                    .finish(report.worker_id, Instant::now());
+            }
+            return commit;
+        }
+
+        self.workers
+            .lock()
+            .unwrap()
+            .finish(report.worker_id, Instant::now());
+        self.release_commit(&task, report.worker_id);
+        match outcome {
+            Some(TaskOutcome::Committed) => {
+                // Map output stays with the worker that committed it
+                let output = task.is_map.then_some(MapOutput {
+                    worker_id: report.worker_id,
+                    addr: shuffle_addr,
+                });
+                self.mark_done(client, &task, output).await;
+                let progress = self.scheduler.lock().unwrap().progress(task.is_map);
+                println!(
+                    "INFO: Worker {} completed task {} ({} phase: {}).",
+                    report.worker_id,
+                    task.name,
+                    phase_name(task.is_map),
+                    progress
+                );
+                self.publish(Some(Event::TaskCompleted(TaskCompleted {
+                    name: task.name.clone(),
+                    is_map: task.is_map,
+                    worker_id: report.worker_id,
+                })));
+            }
+            Some(TaskOutcome::FailedUserCode) => {
+                let bad_record = Some(report.bad_record).filter(|offset| *offset >= 0);
+                self.fail(
+                    client,
+                    &task,
+                    report.worker_id,
+                    true,
+                    bad_record,
+                    &report.error,
                 )
                 .await;
+            }
+            Some(TaskOutcome::FetchFailed) => {
+                self.fetch_failed(
+                    client,
+                    &task,
+                    report.worker_id,
+                    &report.failed_location,
+                    &report.error,
+                )
+                .await;
+            }
+            Some(TaskOutcome::FailedRetryable) | Some(TaskOutcome::Succeeded) | None => {
+                self.fail(client, &task, report.worker_id, false, None, &report.error)
+                    .await;
+            }
+        }
+        false
+    }
+}
 
-                if !(res.1.unwrap()) {
-                    let response_tasknum = &res.0.unwrap();
-                    let tasknum = res.3.unwrap();
-                    let reply = TaskResponse {
-                        // file_name is a no-op for reduce tasks, as we use the reduce task num
-                        task_name: response_tasknum.to_string(),
-                        is_assigned: false,
-                        is_map: false,
-                        tasknum,
-                        done: false,
-                    };
+// Serves workers the tasks of every job the master is running. Workers are given tasks of the
+// earliest started job that has any to hand out, so jobs run back to back, and overlap while
+// the last tasks of one job are running.
+#[derive(Clone, Debug)]
+pub struct TaskService {
+    // Long-lived handle to the store, shared by every request
+    client: Client,
+    workers: Arc<Mutex<WorkerRegistry>>,
+    // Jobs in the order they were started, including the ones that have finished
+    jobs: Arc<Mutex<Vec<JobService>>>,
+    // Jobs in the order they were started, until the workers have been told they finished
+    live_jobs: Arc<Mutex<Vec<JobService>>>,
+    // Ids and output directories of the jobs being submitted, reserved until they are started
+    submitting: Arc<Mutex<HashMap<String, PathBuf>>>,
+    // Whether the master keeps serving once its jobs have finished, waiting for more to be
+    // submitted. Otherwise workers are told to exit once there is nothing left to run.
+    persistent: bool,
+}
+impl TaskService {
+    pub fn new(client: Client, persistent: bool) -> TaskService {
+        TaskService {
+            client,
+            workers: Arc::new(Mutex::new(WorkerRegistry::new())),
+            jobs: Arc::new(Mutex::new(Vec::new())),
+            live_jobs: Arc::new(Mutex::new(Vec::new())),
+            submitting: Arc::new(Mutex::new(HashMap::new())),
+            persistent,
+        }
+    }
+
+    // Loads a job recorded in the store and starts handing out its tasks
+    pub async fn start_job(&self, job_id: &str, options: JobOptions) -> JobService {
+        let mut job = JobService::new(self.client.clone(), self.workers.clone(), job_id, options);
+        job.load().await;
+        self.jobs.lock().unwrap().push(job.clone());
+        self.live_jobs.lock().unwrap().push(job.clone());
+        job
+    }
+
+    fn job(&self, job_id: &str) -> Option<JobService> {
+        let jobs = self.jobs.lock().unwrap();
+        jobs.iter().find(|job| job.job_id == job_id).cloned()
+    }
+
+    fn jobs(&self) -> Vec<JobService> {
+        self.jobs.lock().unwrap().clone()
+    }
 
-                    update_assigned(&client, DB_NAME, REDUCE_TASKS_COLL, response_tasknum, true)
-                        .await;
-                    return Ok(Response::new(reply));
+    // Returns the jobs that are running, after queueing the ones that have finished since to be
+    // told to every worker, which removes their map output
+    fn live_jobs(&self) -> Vec<JobService> {
+        let mut finished = Vec::new();
+        let live = {
+            let mut live_jobs = self.live_jobs.lock().unwrap();
+            live_jobs.retain(|job| {
+                let running = job.running();
+                if !running {
+                    finished.push(job.job_id.clone());
                 }
+                running
+            });
+            live_jobs.clone()
+        };
+        let mut workers = self.workers.lock().unwrap();
+        for job_id in &finished {
+            workers.job_finished(job_id);
+        }
+        live
+    }
+
+    // Reserves a job id and output directory for a job being submitted, unless a job that was
+    // started or is being submitted already has either
+    fn reserve(&self, job_id: &str, output_dir: &Path) -> Result<(), String> {
+        let jobs = self.jobs.lock().unwrap();
+        let mut submitting = self.submitting.lock().unwrap();
+        let started = jobs.iter().map(|job| (&job.job_id, &job.output_dir));
+        for (other_id, other_dir) in started.chain(submitting.iter()) {
+            if other_id == job_id {
+                return Err(format!("job {job_id} already exists"));
             }
+            if other_dir == output_dir {
+                return Err(format!(
+                    "job {} already writes its output to {}",
+                    other_id,
+                    output_dir.display()
+                ));
+            }
+        }
+        submitting.insert(job_id.to_string(), output_dir.to_path_buf());
+        Ok(())
+    }
+
+    // Releases the reservation of a job that was started or failed to be
+    fn release(&self, job_id: &str) {
+        self.submitting.lock().unwrap().remove(job_id);
+    }
+
+    // Returns true while any job is running
+    pub fn running(&self) -> bool {
+        self.jobs().iter().any(JobService::running)
+    }
+
+    // Workers must register before asking for tasks, and are removed from the registry after
+    // missing their heartbeats
+    fn is_registered(&self, id: u64) -> bool {
+        self.workers.lock().unwrap().contains(id)
+    }
+
+    // Removes a drained worker once it has finished its task, returning the response telling it
+    // to exit. The map output it holds goes with it, so those map tasks run again.
+    async fn release_drained(&self, id: u64) -> TaskResponse {
+        self.workers.lock().unwrap().remove(id);
+        let reason = format!("worker {id} was drained");
+        for job in self.live_jobs() {
+            let lost = job.map_outputs.lock().unwrap().remove_worker(id);
+            job.rerun_maps(&self.client, &lost, &reason).await;
+        }
+        println!("INFO: Worker {id} was drained, telling it to exit.");
+        action_response(Action::Exit(Exit {
+            completed: false,
+            reason,
+            drained: true,
+        }))
+    }
+
+    // Response to a worker asking for a task: a task of the earliest job that has one for it,
+    // or else telling it to wait for one, or to exit once every job has finished and no more
+    // can be submitted
+    async fn next_response(&self, worker_id: u64) -> TaskResponse {
+        for job in self.live_jobs() {
+            if let Some(reply) = job.next_task(&self.client, worker_id).await {
+                return reply;
+            }
+        }
+
+        let jobs = self.jobs();
+        if !self.persistent && !jobs.iter().any(JobService::running) {
+            return match jobs.iter().find_map(JobService::abort_reason) {
+                Some(reason) => exit_response(false, reason),
+                None => exit_response(true, String::new()),
+            };
+        }
+        // Every remaining task is running. Reduce tasks wait for their job's map phase, and
+        // running tasks may still fail and need to be retried.
+        action_response(Action::Wait(Wait {
+            retry_after_ms: WAIT_INTERVAL.as_millis() as u64,
+        }))
+    }
+}
+
+#[tonic::async_trait]
+impl Task for TaskService {
+    async fn send_task(
+        &self,
+        request: Request<TaskRequest>,
+    ) -> Result<Response<TaskResponse>, Status> {
+        let worker_id = request.get_ref().worker_id;
+        println!("DEBUG: Master got a request from worker {}", worker_id);
+        if !self.is_registered(worker_id) {
+            return Err(not_registered(worker_id));
+        }
+
+        let draining = self
+            .workers
+            .lock()
+            .unwrap()
+            .get(worker_id)
+            .is_some_and(|worker| worker.draining);
+        let mut reply = if draining {
+            self.release_drained(worker_id).await
+        } else {
+            self.next_response(worker_id).await
+        };
+        // Workers remove the map output of finished jobs, and are told about each job once
+        self.live_jobs();
+        reply.finished_jobs = self.workers.lock().unwrap().take_finished_jobs(worker_id);
+        Ok(Response::new(reply))
+    }
+
+    async fn register(
+        &self,
+        request: Request<RegisterRequest>,
+    ) -> Result<Response<RegisterResponse>, Status> {
+        let register = request.get_ref();
+        if !version::compatible(register.protocol_version) {
+            eprintln!(
+                "WARN: Rejected worker process {} on {}: protocol version {} (build {}) is not supported.",
+                register.pid, register.hostname, register.protocol_version, register.build_version
+            );
+            return Err(Status::failed_precondition(format!(
+                "Worker speaks protocol version {} but master {} supports versions {} to {}. Run a worker built from the same release as the master.",
+                register.protocol_version,
+                version::BUILD_VERSION,
+                version::MIN_PROTOCOL_VERSION,
+                version::PROTOCOL_VERSION
+            )));
+        }
+        let worker_id = self.workers.lock().unwrap().register(
+            register.pid,
+            &register.hostname,
+            &register.shuffle_addr,
+            Instant::now(),
+        );
+        println!(
+            "INFO: Registered worker {} (process {} on {}, serving map output on {}).",
+            worker_id, register.pid, register.hostname, register.shuffle_addr
+        );
+        Ok(Response::new(RegisterResponse {
+            worker_id,
+            protocol_version: version::PROTOCOL_VERSION,
+            build_version: version::BUILD_VERSION.to_string(),
+        }))
+    }
+
+    async fn heartbeat(
+        &self,
+        request: Request<HeartbeatRequest>,
+    ) -> Result<Response<HeartbeatResponse>, Status> {
+        let heartbeat = request.get_ref();
+        let known = self.workers.lock().unwrap().heartbeat(
+            heartbeat.worker_id,
+            heartbeat.tasks_completed,
+            Instant::now(),
+        );
+        if !known {
+            return Err(not_registered(heartbeat.worker_id));
+        }
+        // Nothing will use the output of a task of a job that is no longer running
+        let abort_task = !heartbeat.task_name.is_empty()
+            && self
+                .job(&heartbeat.job_id)
+                .is_some_and(|job| !job.running());
+        Ok(Response::new(HeartbeatResponse { abort_task }))
+    }
+
+    async fn report_task(
+        &self,
+        request: Request<TaskReport>,
+    ) -> Result<Response<TaskReportResponse>, Status> {
+        let report = request.get_ref();
+        let shuffle_addr = self
+            .workers
+            .lock()
+            .unwrap()
+            .get(report.worker_id)
+            .map(|worker| worker.shuffle_addr.clone());
+        let Some(shuffle_addr) = shuffle_addr else {
+            return Err(not_registered(report.worker_id));
+        };
+        let job = self
+            .job(&report.job_id)
+            .ok_or_else(|| no_such_job(&report.job_id))?;
+        let commit = job.report(&self.client, report, shuffle_addr).await;
+        Ok(Response::new(TaskReportResponse { commit }))
+    }
+}
+
+// Serves the Admin RPCs for the jobs the master is running
+#[derive(Clone, Debug)]
+pub struct AdminService {
+    tasks: TaskService,
+}
+impl AdminService {
+    pub fn new(tasks: TaskService) -> AdminService {
+        AdminService { tasks }
+    }
+
+    // Records a submitted job in the store and starts running it, returning its id
+    async fn submit(&self, request: SubmitJobRequest) -> Result<String, String> {
+        let options = match request.options {
+            Some(options) => JobOptions {
+                max_attempts: options.max_attempts,
+                max_user_failures: options.max_user_failures,
+                skip_bad_records: options.skip_bad_records,
+                backup_threshold: options.backup_threshold,
+            },
+            None => JobOptions::default(),
+        };
+        let job = Job {
+            n_map: request.n_map,
+            n_reduce: request.n_reduce,
+            inputs: request.inputs,
+            options,
+        };
+        job.check()?;
+        if let Some(input) = job.inputs.iter().find(|input| !within_master_dir(input)) {
+            return Err(format!(
+                "invalid input file {input}, expected a relative path without .."
+            ));
+        }
+        let job_id = if request.job_id.is_empty() {
+            new_job_id()
+        } else if job::valid_job_id(&request.job_id) {
+            request.job_id
+        } else {
+            return Err(format!(
+                "invalid job id {}, expected letters, digits, - and _",
+                request.job_id
+            ));
+        };
+
+        // Every job has an output directory of its own, so that jobs running side by side do
+        // not overwrite each other's output, and cancelling a job removes only its own
+        let output_dir = submitted_output_dir(&master_dir(), &request.output_dir, &job_id)?;
+        if fs::read_dir(&output_dir).is_ok_and(|mut entries| entries.next().is_some()) {
+            return Err(format!(
+                "output directory {} is not empty",
+                output_dir.display()
+            ));
+        }
+        // Reserved before anything is awaited, so that concurrent submissions cannot both take
+        // the same id or output directory
+        self.tasks.reserve(&job_id, &output_dir)?;
+        let started = self
+            .start_submitted(&job_id, job, &request.name, &output_dir)
+            .await;
+        self.tasks.release(&job_id);
+        started?;
+        println!(
+            "INFO: Accepted job {} ({}), writing its output to {}.",
+            job_id,
+            request.name,
+            output_dir.display()
+        );
+        Ok(job_id)
+    }
+
+    // Records a submitted job whose id and output directory were reserved, and starts it
+    async fn start_submitted(
+        &self,
+        job_id: &str,
+        job: Job,
+        name: &str,
+        output_dir: &Path,
+    ) -> Result<(), String> {
+        let client = &self.tasks.client;
+        if job_exists(client, job_id).await {
+            return Err(format!("job {job_id} already exists"));
+        }
+        fs::create_dir_all(output_dir).map_err(|err| {
+            format!(
+                "could not create output directory {}: {err}",
+                output_dir.display()
+            )
+        })?;
+
+        let options = init_job(client, job_id, job, name, output_dir).await;
+        self.tasks.start_job(job_id, options).await;
+        Ok(())
+    }
+}
+
+// Sends a client watching a job the events it opens with, then the job's events until it has
+// finished or the client has gone away
+async fn forward_events(
+    opening: Vec<JobEvent>,
+    mut events: broadcast::Receiver<JobEvent>,
+    sender: mpsc::Sender<Result<JobEvent, Status>>,
+) {
+    let finished = |event: &JobEvent| matches!(event.event, Some(Event::JobFinished(_)));
+    for event in opening {
+        let last = finished(&event);
+        if sender.send(Ok(event)).await.is_err() || last {
+            return;
+        }
+    }
+    loop {
+        let event = match events.recv().await {
+            Ok(event) => event,
+            // The next event carries up to date progress
+            Err(broadcast::error::RecvError::Lagged(_)) => continue,
+            Err(broadcast::error::RecvError::Closed) => return,
+        };
+        let last = finished(&event);
+        if sender.send(Ok(event)).await.is_err() || last {
+            return;
+        }
+    }
+}
+
+#[tonic::async_trait]
+impl Admin for AdminService {
+    type WatchJobStream = ReceiverStream<Result<JobEvent, Status>>;
+
+    async fn get_job_status(
+        &self,
+        request: Request<GetJobStatusRequest>,
+    ) -> Result<Response<JobStatus>, Status> {
+        let job_id = &request.get_ref().job_id;
+        let job = self.tasks.job(job_id).ok_or_else(|| no_such_job(job_id))?;
+        Ok(Response::new(job.job_status().await))
+    }
+
+    async fn list_jobs(
+        &self,
+        _request: Request<ListJobsRequest>,
+    ) -> Result<Response<ListJobsResponse>, Status> {
+        let jobs = self
+            .tasks
+            .jobs()
+            .iter()
+            .map(JobService::job_summary)
+            .collect();
+        Ok(Response::new(ListJobsResponse { jobs }))
+    }
+
+    async fn cancel_job(
+        &self,
+        request: Request<CancelJobRequest>,
+    ) -> Result<Response<CancelJobResponse>, Status> {
+        let job_id = &request.get_ref().job_id;
+        let job = self.tasks.job(job_id).ok_or_else(|| no_such_job(job_id))?;
+        let cancelled = job.cancel().await;
+        if cancelled {
+            println!("INFO: Job {job_id} was cancelled.");
         }
+        Ok(Response::new(CancelJobResponse { cancelled }))
+    }
 
-        // No avaialble tasks found; either mapreduce is done or all map tasks are still in progress
-        return Err(Status::not_found("MapReduce is complete."));
+    async fn drain_worker(
+        &self,
+        request: Request<DrainWorkerRequest>,
+    ) -> Result<Response<DrainWorkerResponse>, Status> {
+        let worker_id = request.get_ref().worker_id;
+        if !self.tasks.workers.lock().unwrap().drain(worker_id) {
+            return Err(Status::not_found(format!(
+                "Worker {worker_id} is not registered."
+            )));
+        }
+        println!("INFO: Draining worker {worker_id}, it will exit once its task is done.");
+        Ok(Response::new(DrainWorkerResponse {}))
+    }
+
+    async fn submit_job(
+        &self,
+        request: Request<SubmitJobRequest>,
+    ) -> Result<Response<SubmitJobResponse>, Status> {
+        if !self.tasks.persistent {
+            return Err(Status::failed_precondition(
+                "The master only runs the job it was started with, start it with --serve to submit jobs.",
+            ));
+        }
+        let job_id = self
+            .submit(request.into_inner())
+            .await
+            .map_err(Status::invalid_argument)?;
+        Ok(Response::new(SubmitJobResponse { job_id }))
+    }
+
+    async fn watch_job(
+        &self,
+        request: Request<WatchJobRequest>,
+    ) -> Result<Response<Self::WatchJobStream>, Status> {
+        let job_id = &request.get_ref().job_id;
+        let job = self.tasks.job(job_id).ok_or_else(|| no_such_job(job_id))?;
+        let (events, opening) = job.watch();
+        let (sender, receiver) = mpsc::channel(EVENT_BACKLOG);
+        tokio::spawn(forward_events(opening, events, sender));
+        Ok(Response::new(ReceiverStream::new(receiver)))
+    }
+}
+
+// Periodically removes workers that have missed their heartbeats from the registry, returning
+// the tasks they were running to the idle pool so that other workers can pick them up. Map
+// output held by those workers is lost with them, so the map tasks that produced it are run
+// again.
+async fn reap_dead_workers(task_service: TaskService) {
+    let client = &task_service.client;
+    loop {
+        tokio::time::sleep(POLL_INTERVAL).await;
+
+        let dead = task_service
+            .workers
+            .lock()
+            .unwrap()
+            .expire(Instant::now(), HEARTBEAT_TIMEOUT);
+        for (id, worker) in dead {
+            eprintln!(
+                "WARN: Worker {} (process {} on {}) missed its heartbeats, removing it.",
+                id, worker.pid, worker.hostname
+            );
+            let error = format!("worker {} was lost", id);
+            if let Some(running) = worker.current_task {
+                if let Some(job) = task_service.job(&running.job_id) {
+                    let task = running.task;
+                    job.release_commit(&task, id);
+                    let state = job.scheduler.lock().unwrap().state(&task);
+                    if state.is_some_and(|state| state != TaskState::Completed) {
+                        job.fail(client, &task, id, false, None, &error).await;
+                    }
+                }
+            }
+
+            for job in task_service.jobs() {
+                let lost = job.map_outputs.lock().unwrap().remove_worker(id);
+                job.rerun_maps(client, &lost, &error).await;
+            }
+        }
+    }
+}
+
+// Keeps the master's standard gRPC health statuses up to date. The server as a whole ("") and
+// the `tasks.Task` service are serving while any job is running, or for as long as the master
+// accepts jobs if it was started with --serve. The "map" and "reduce" statuses report whether
+// any job is handing out tasks of that phase, so that supervisors can tell how jobs are getting
+// on.
+async fn report_health(task_service: TaskService, mut reporter: HealthReporter) {
+    let status = |serving: bool| {
+        if serving {
+            ServingStatus::Serving
+        } else {
+            ServingStatus::NotServing
+        }
+    };
+    loop {
+        let running = task_service.persistent || task_service.running();
+        let phases: Vec<Option<bool>> = task_service
+            .jobs()
+            .iter()
+            .filter(|job| job.running())
+            .map(|job| job.scheduler.lock().unwrap().current_phase())
+            .collect();
+        reporter.set_service_status("", status(running)).await;
+        reporter
+            .set_service_status("tasks.Task", status(running))
+            .await;
+        for is_map in [true, false] {
+            let serving = phases.contains(&Some(is_map));
+            reporter
+                .set_service_status(phase_name(is_map), status(serving))
+                .await;
+        }
+        if !running {
+            return;
+        }
+        tokio::time::sleep(POLL_INTERVAL).await;
     }
 }
 
 #[derive(Debug)]
 pub struct Master<'a> {
     name: &'a str,
+    addr: ListenAddr,
+    tls: Option<ServerTls>,
+    // Token workers must present, if any
+    token: Option<String>,
 }
-impl Master<'_> {
-    pub fn new(name: &str) -> Master {
-        Master { name }
+impl<'a> Master<'a> {
+    pub fn new(
+        name: &'a str,
+        addr: ListenAddr,
+        tls: Option<ServerTls>,
+        token: Option<String>,
+    ) -> Master<'a> {
+        Master {
+            name,
+            addr,
+            tls,
+            token,
+        }
     }
     pub fn get_name(&self) -> &str {
         self.name
     }
-    pub async fn boot(&self) -> Result<(), Box<dyn std::error::Error>> {
-        let addr = "[::1]:50051".parse()?;
-        let task_service = TaskService::default();
 
-        Server::builder()
-            .add_service(TaskServer::new(task_service))
-            .serve(addr)
-            .await?;
-        Ok(())
+    // Serves the tasks of a job recorded in the store to workers until the job is complete or
+    // aborted, then shuts down. Without a job, serves the jobs submitted to it until the process
+    // is stopped.
+    pub async fn boot(
+        &self,
+        client: &Client,
+        job: Option<(&str, JobOptions)>,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let task_service = TaskService::new(client.clone(), job.is_none());
+        let job = match job {
+            Some((job_id, options)) => Some(task_service.start_job(job_id, options).await),
+            None => None,
+        };
+        tokio::spawn(reap_dead_workers(task_service.clone()));
+
+        let shutdown = async {
+            while task_service.persistent || task_service.running() {
+                tokio::time::sleep(POLL_INTERVAL).await;
+            }
+            // Give waiting workers the chance to ask again and be told to exit
+            tokio::time::sleep(2 * WAIT_INTERVAL).await;
+            println!("INFO: Shutting down {}.", self.name);
+        };
+        // Health checks and reflection carry nothing secret, so probes need no token
+        let (health_reporter, health_service) = tonic_health::server::health_reporter();
+        tokio::spawn(report_health(task_service.clone(), health_reporter));
+        let reflection_service = tonic_reflection::server::Builder::configure()
+            .register_encoded_file_descriptor_set(tasks::FILE_DESCRIPTOR_SET)
+            .register_encoded_file_descriptor_set(
+                tonic_health::proto::GRPC_HEALTH_V1_FILE_DESCRIPTOR_SET,
+            )
+            .build()?;
+
+        let mut server = Server::builder();
+        if let Some(tls) = &self.tls {
+            server = server.tls_config(tls.config()?)?;
+        }
+        let check_token = CheckToken::new(self.token.as_deref());
+        let router = server
+            .add_service(TaskServer::with_interceptor(
+                task_service.clone(),
+                check_token.clone(),
+            ))
+            .add_service(AdminServer::with_interceptor(
+                AdminService::new(task_service.clone()),
+                check_token,
+            ))
+            .add_service(health_service)
+            .add_service(reflection_service);
+        match &self.addr {
+            ListenAddr::Tcp(addr) => router.serve_with_shutdown(*addr, shutdown).await?,
+            ListenAddr::Unix(path) => {
+                let incoming = transport::bind_unix(path)?;
+                router
+                    .serve_with_incoming_shutdown(incoming, shutdown)
+                    .await?;
+                let _ = fs::remove_file(path);
+            }
+        }
+
+        match job.map(|job| (job.job_state(), job.abort_reason())) {
+            Some((JobState::Cancelled, _)) => Err("Job cancelled".into()),
+            Some((_, Some(reason))) => Err(format!("Job aborted: {reason}").into()),
+            _ => {
+                println!("INFO: MapReduce is complete.");
+                Ok(())
+            }
+        }
     }
 }
 
-#[tokio::main]
-async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let args: Vec<String> = env::args().collect();
+// Returns an id for a new job, unique among the jobs started on this machine. Masters serving
+// submitted jobs can start several in the same second, which the count tells apart.
+fn new_job_id() -> String {
+    static STARTED: AtomicU64 = AtomicU64::new(0);
+    let started = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    match STARTED.fetch_add(1, Ordering::Relaxed) {
+        0 => format!("{}-{}", started.as_secs(), process::id()),
+        count => format!("{}-{}-{}", started.as_secs(), process::id(), count),
+    }
+}
+
+// Returns a random token for a job's reduce tasks to fetch its map output with
+fn new_shuffle_token() -> String {
+    let mut bytes = [0; 16];
+    fs::File::open("/dev/urandom")
+        .and_then(|mut random| random.read_exact(&mut bytes))
+        .unwrap_or_else(|err| {
+            eprintln!("ERROR: Could not generate a shuffle token: {err}");
+            exit(1)
+        });
+    bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+}
 
-    let n_map: i64 = FromStr::from_str(&args[1]).unwrap();
-    let n_reduce: i64 = FromStr::from_str(&args[2]).unwrap();
+// Records a new job and its tasks, returning the job's options. Safe to repeat after a crash
+// part way through: tasks that were already recorded are kept, and the state record, which marks
+// the job as initialized, is written last.
+async fn init_job(
+    client: &Client,
+    job_id: &str,
+    job: Job,
+    name: &str,
+    output_dir: &Path,
+) -> JobOptions {
+    let db_name = mongo_utils::job_db_name(job_id);
     let mut map_tasks: HashMap<String, (bool, bool)> = HashMap::new();
     let mut reduce_tasks: HashMap<String, (bool, bool)> = HashMap::new();
 
     // Map tasks are identified by their file name
-    for i in 3..args.len() {
-        map_tasks.insert(args[i].clone(), (false, true));
+    for filename in &job.inputs {
+        map_tasks.insert(filename.clone(), (false, true));
     }
 
     // Reduce tasks are identified by their reduce task num
-    for i in 0..n_reduce {
+    for i in 0..job.n_reduce {
         reduce_tasks.insert(i.to_string(), (false, false));
     }
 
-    let master: Master = Master::new("mymaster");
-
-    let client_options = ClientOptions::parse(MONGO_HOST)
-        .await
-        .unwrap_or_else(|err| {
-            eprintln!("ERROR: could not parse address: {err}");
-            exit(1)
-        });
-    let client = Client::with_options(client_options).unwrap_or_else(|err| {
-        eprintln!("ERROR: could not initialize client: {err}");
-        exit(1)
-    });
+    for coll_name in [STATE_COLL, MAP_TASKS_COLL, REDUCE_TASKS_COLL] {
+        mongo_utils::create_collection(client, &db_name, coll_name).await;
+        mongo_utils::create_unique_index(client, &db_name, coll_name, "name").await;
+    }
+    mongo_utils::init_tasks(client, &db_name, MAP_TASKS_COLL, &map_tasks).await;
+    mongo_utils::init_tasks(client, &db_name, REDUCE_TASKS_COLL, &reduce_tasks).await;
 
-    mongo_utils::create_collection(&client, DB_NAME, "state").await;
     mongo_utils::init_master_state(
-        &client,
-        DB_NAME,
-        "state",
-        "current_master_state",
-        n_map,
-        n_reduce,
+        client,
+        &db_name,
+        STATE_COLL,
+        STATE_RECORD,
+        job.n_map,
+        job.n_reduce,
+        mongodb::bson::to_document(&job.options).unwrap(),
     )
     .await;
+    for (field, value) in [
+        ("job_name", name.to_string()),
+        ("output_dir", output_dir.to_string_lossy().into_owned()),
+        ("shuffle_token", new_shuffle_token()),
+    ] {
+        mongo_utils::update_str(client, &db_name, STATE_COLL, STATE_RECORD, field, &value).await;
+    }
+    recount_progress(client, &db_name).await;
 
-    mongo_utils::init_tasks(&client, DB_NAME, MAP_TASKS_COLL, &map_tasks).await;
-    mongo_utils::init_tasks(&client, DB_NAME, REDUCE_TASKS_COLL, &reduce_tasks).await;
+    println!(
+        "INFO: Started job {}. If the master crashes, restart it with `master --resume {}`.",
+        job_id, job_id
+    );
+    job.options
+}
 
-    master
-        .boot()
+// Reloads the options of a job started by a previous master, and returns the tasks that master
+// had handed out but not seen finish to the idle pool. Completed tasks are kept, so their work
+// is not redone.
+async fn resume_job(client: &Client, job_id: &str) -> Result<JobOptions, String> {
+    let db_name = mongo_utils::job_db_name(job_id);
+    let options = mongo_utils::get_doc(client, &db_name, STATE_COLL, STATE_RECORD, "options")
+        .await
+        .ok_or_else(|| format!("no job with id {job_id} was found"))?;
+    let options: JobOptions = mongodb::bson::from_document(options)
+        .map_err(|err| format!("could not read the options of job {job_id}: {err}"))?;
+    let cancelled = mongo_utils::get_record(client, &db_name, STATE_COLL, STATE_RECORD)
         .await
-        .expect("ERROR: Could not boot master process.");
+        .is_some_and(|state| state.get_bool("cancelled").unwrap_or(false));
+    if cancelled {
+        return Err(format!(
+            "job {job_id} was cancelled, pass --if-exists overwrite to start it over"
+        ));
+    }
 
-    // // TODO: Poll master every 5 seconds to check completion status
-    // let five_seconds = time::Duration::from_millis(5000);
-    // while !master.done() {
-    //     println!("master not done!");
-    //     thread::sleep(five_seconds);
-    // }
+    for is_map in [true, false] {
+        let reset = mongo_utils::reset_unfinished(client, &db_name, tasks_coll(is_map)).await;
+        println!(
+            "INFO: Resuming job {}: returned {} in-progress {} tasks to the idle pool.",
+            job_id,
+            reset,
+            phase_name(is_map)
+        );
+    }
+    recount_progress(client, &db_name).await;
+    for is_map in [true, false] {
+        let progress = phase_progress(client, &db_name, is_map).await;
+        println!("INFO: {} phase: {}.", phase_name(is_map), progress);
+    }
+    Ok(options)
+}
+
+// Returns true if a job with this id has been initialized
+async fn job_exists(client: &Client, job_id: &str) -> bool {
+    let db_name = mongo_utils::job_db_name(job_id);
+    mongo_utils::get_val(client, &db_name, STATE_COLL, STATE_RECORD, "n_map")
+        .await
+        .is_some()
+}
+
+// Starts, resumes or restarts the job given on the command line, as decided by --if-exists,
+// returning its id and options
+async fn prepare_job(client: &Client, master_args: MasterArgs) -> (String, JobOptions) {
+    let job_id = master_args.job_id.unwrap_or_else(new_job_id);
+    let exists = job_exists(client, &job_id).await;
+    let dir = master_dir();
+    let options = match (exists, master_args.if_exists, master_args.job) {
+        (true, IfExists::Refuse, _) => {
+            eprintln!(
+                "ERROR: Job {job_id} already exists, pass --if-exists resume to continue it or --if-exists overwrite to start it over."
+            );
+            exit(1)
+        }
+        (true, IfExists::Resume, _) => resume_job(client, &job_id).await.unwrap_or_else(|err| {
+            eprintln!("ERROR: {err}");
+            exit(1)
+        }),
+        (true, IfExists::Overwrite, Some(job)) => {
+            println!("INFO: Discarding the existing state of job {}.", job_id);
+            mongo_utils::drop_database(client, &mongo_utils::job_db_name(&job_id)).await;
+            init_job(client, &job_id, job, "", &dir).await
+        }
+        (false, _, Some(job)) => init_job(client, &job_id, job, "", &dir).await,
+        (_, _, None) => {
+            eprintln!("ERROR: No job with id {job_id} was found.");
+            exit(1)
+        }
+    };
+    (job_id, options)
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let args: Vec<String> = env::args().collect();
+    let master_args = MasterArgs::from_args(&args[1..]).unwrap_or_else(|err| {
+        eprintln!(
+            "ERROR: {err}\nusage: master {}\n\njob: {}",
+            MasterArgs::USAGE,
+            Job::USAGE
+        );
+        exit(2)
+    });
+    let client = connect().await;
+
+    let master: Master = Master::new(
+        "mymaster",
+        master_args.listen.clone(),
+        master_args.tls.clone(),
+        transport::token_from_env(),
+    );
+    let result = if master_args.serve {
+        println!(
+            "INFO: Serving workers on {}, waiting for jobs to be submitted.",
+            master_args.listen
+        );
+        master.boot(&client, None).await
+    } else {
+        let (job_id, options) = prepare_job(&client, master_args.clone()).await;
+        println!("INFO: Serving workers on {}.", master_args.listen);
+        master.boot(&client, Some((&job_id, options))).await
+    };
+    if let Err(err) = result {
+        eprintln!("ERROR: {err}");
+        exit(1);
+    }
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Adds a job with the given map tasks to `tasks`, without loading anything from the store
+    fn add_job(tasks: &TaskService, job_id: &str, states: &[TaskState]) -> JobService {
+        let mut job = JobService::new(
+            tasks.client.clone(),
+            tasks.workers.clone(),
+            job_id,
+            JobOptions::default(),
+        );
+        job.n_map = states.len() as i64;
+        job.n_reduce = 1;
+        job.output_dir = master_dir().join(job_id);
+        {
+            let mut scheduler = job.scheduler.lock().unwrap();
+            for (tasknum, state) in states.iter().enumerate() {
+                let task = TaskRef {
+                    name: format!("{job_id}-{tasknum}.txt"),
+                    is_map: true,
+                };
+                scheduler.add(task, tasknum as i32, *state);
+            }
+        }
+        tasks.jobs.lock().unwrap().push(job.clone());
+        tasks.live_jobs.lock().unwrap().push(job.clone());
+        job
+    }
+
+    fn register(tasks: &TaskService) -> u64 {
+        let mut workers = tasks.workers.lock().unwrap();
+        workers.register(1, "localhost", "[::1]:1", Instant::now())
+    }
+
+    #[tokio::test]
+    async fn test_watch() {
+        let tasks = TaskService::new(connect().await, true);
+        let job = add_job(&tasks, "job1", &[TaskState::Idle]);
+        let map = TaskRef {
+            name: "job1-0.txt".to_string(),
+            is_map: true,
+        };
+        let reduce = TaskRef {
+            name: "0".to_string(),
+            is_map: false,
+        };
+        job.scheduler
+            .lock()
+            .unwrap()
+            .add(reduce.clone(), 0, TaskState::Idle);
+        let event = |event: &JobEvent| event.event.clone().unwrap();
+
+        // A client starts with the current phase, which is not sent to it again
+        let (mut first, opening) = job.watch();
+        let opening: Vec<Event> = opening.iter().map(event).collect();
+        assert_eq!(
+            opening,
+            [Event::PhaseChanged(PhaseChanged { is_map: true })]
+        );
+        job.publish(None);
+        assert!(first.try_recv().is_err());
+
+        // Changes of phase are sent once, however many times events are published
+        job.scheduler.lock().unwrap().complete(&map);
+        job.publish(None);
+        job.publish(None);
+        let phase_changed = Event::PhaseChanged(PhaseChanged { is_map: false });
+        assert_eq!(event(&first.try_recv().unwrap()), phase_changed);
+        assert!(first.try_recv().is_err());
+
+        // A client watching from then on starts with the new phase instead
+        let (mut second, opening) = job.watch();
+        let opening: Vec<Event> = opening.iter().map(event).collect();
+        assert_eq!(opening, [phase_changed]);
+
+        // Both get the outcome of the job once it finishes, and nothing after it
+        job.scheduler.lock().unwrap().complete(&reduce);
+        job.publish(None);
+        job.publish(None);
+        for receiver in [&mut first, &mut second] {
+            let finished = event(&receiver.try_recv().unwrap());
+            assert_eq!(finished, job.finished_event(JobState::Completed));
+            assert!(receiver.try_recv().is_err());
+        }
+        let (_, opening) = job.watch();
+        let opening: Vec<Event> = opening.iter().map(event).collect();
+        assert_eq!(opening, [job.finished_event(JobState::Completed)]);
+    }
+
+    #[test]
+    fn test_submitted_output_dir() {
+        let dir = Path::new("/srv/mrlite");
+        let output_dir = |output_dir| submitted_output_dir(dir, output_dir, "job1");
+        assert_eq!(output_dir(""), Ok(dir.join("job1")));
+        assert_eq!(output_dir("out"), Ok(dir.join("out")));
+        assert_eq!(output_dir("./out/wc"), Ok(dir.join("out/wc")));
+
+        // Submitted jobs cannot write outside the master's directory
+        assert!(output_dir("/tmp/out").is_err());
+        assert!(output_dir("..").is_err());
+        assert!(output_dir("out/../../etc").is_err());
+        assert!(within_master_dir("data/./a.txt"));
+        assert!(!within_master_dir("/etc/passwd"));
+    }
+
+    #[tokio::test]
+    async fn test_submit_rejects() {
+        let tasks = TaskService::new(connect().await, true);
+        add_job(&tasks, "running", &[TaskState::Idle]);
+        add_job(&tasks, "done", &[TaskState::Completed]);
+        let admin = AdminService::new(tasks);
+        let request = |job_id: &str, n_map, input: &str, output_dir: &str| SubmitJobRequest {
+            job_id: job_id.to_string(),
+            n_map,
+            n_reduce: 1,
+            inputs: vec![input.to_string()],
+            output_dir: output_dir.to_string(),
+            ..SubmitJobRequest::default()
+        };
+
+        // Every one is rejected before anything is written to the store
+        for (request, error) in [
+            (request("job1", 2, "a.txt", ""), "n_map must be"),
+            (request("job1", 1, "/etc/passwd", ""), "invalid input file"),
+            (request("job1", 1, "../a.txt", ""), "invalid input file"),
+            (request("job/1", 1, "a.txt", ""), "invalid job id"),
+            (request("running", 1, "a.txt", ""), "already exists"),
+            (
+                request("job1", 1, "a.txt", "/tmp/out"),
+                "invalid output directory",
+            ),
+            (
+                request("job1", 1, "a.txt", "out/.."),
+                "invalid output directory",
+            ),
+            (
+                request("job1", 1, "a.txt", "running"),
+                "job running already writes",
+            ),
+            (
+                request("job1", 1, "a.txt", "done"),
+                "job done already writes",
+            ),
+            (request("job1", 1, "a.txt", "src"), "is not empty"),
+        ] {
+            let err = admin.submit(request).await.unwrap_err();
+            assert!(err.contains(error), "{err}");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_reserve() {
+        let tasks = TaskService::new(connect().await, true);
+        add_job(&tasks, "started", &[TaskState::Idle]);
+        let dir = master_dir();
+        assert!(tasks.reserve("job1", &dir.join("out1")).is_ok());
+
+        // Jobs being submitted keep their id and output directory until they are started
+        for (job_id, output_dir, error) in [
+            ("job1", "out2", "job job1 already exists"),
+            ("job2", "out1", "job job1 already writes"),
+            ("started", "out2", "job started already exists"),
+            ("job2", "started", "job started already writes"),
+        ] {
+            let err = tasks.reserve(job_id, &dir.join(output_dir)).unwrap_err();
+            assert!(err.contains(error), "{err}");
+        }
+        tasks.release("job1");
+        assert!(tasks.reserve("job2", &dir.join("out1")).is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_multiple_jobs() {
+        let tasks = TaskService::new(connect().await, true);
+        add_job(&tasks, "done", &[TaskState::Completed]);
+        add_job(&tasks, "running", &[TaskState::InProgress]);
+        let id = register(&tasks);
+        assert!(tasks.running());
+
+        // Workers wait for the running job, and are told which jobs have finished
+        let reply = tasks
+            .send_task(Request::new(TaskRequest { worker_id: id }))
+            .await
+            .unwrap()
+            .into_inner();
+        assert!(matches!(reply.action, Some(Action::Wait(_))));
+        assert_eq!(reply.finished_jobs, vec!["done".to_string()]);
+        let reply = tasks
+            .send_task(Request::new(TaskRequest { worker_id: id }))
+            .await
+            .unwrap()
+            .into_inner();
+        assert!(reply.finished_jobs.is_empty());
+
+        // Heartbeats and reports are routed to the job they are for
+        let heartbeat = |job_id: &str| {
+            Request::new(HeartbeatRequest {
+                worker_id: id,
+                job_id: job_id.to_string(),
+                task_name: "0".to_string(),
+                ..HeartbeatRequest::default()
+            })
+        };
+        let abort_task = |response: Response<HeartbeatResponse>| response.into_inner().abort_task;
+        assert!(abort_task(
+            tasks.heartbeat(heartbeat("done")).await.unwrap()
+        ));
+        assert!(!abort_task(
+            tasks.heartbeat(heartbeat("running")).await.unwrap()
+        ));
+        let report = |job_id: &str| {
+            Request::new(TaskReport {
+                worker_id: id,
+                job_id: job_id.to_string(),
+                task_name: "done-0.txt".to_string(),
+                is_map: true,
+                outcome: TaskOutcome::Succeeded as i32,
+                ..TaskReport::default()
+            })
+        };
+        let response = tasks.report_task(report("done")).await.unwrap();
+        assert!(!response.into_inner().commit);
+        let err = tasks.report_task(report("unknown")).await.unwrap_err();
+        assert_eq!(err.code(), tonic::Code::NotFound);
+    }
+
+    #[tokio::test]
+    async fn test_cancel() {
+        let tasks = TaskService::new(connect().await, true);
+        let job = add_job(&tasks, "job1", &[TaskState::InProgress]);
+        let done = add_job(&tasks, "done", &[TaskState::Completed]);
+        let id = register(&tasks);
+        assert!(!done.mark_cancelled());
+        assert_eq!(done.job_state(), JobState::Completed);
+
+        assert!(job.mark_cancelled());
+        assert_eq!(job.job_state(), JobState::Cancelled);
+        assert_eq!(job.abort_reason().unwrap(), "the job was cancelled");
+        assert!(!tasks.running());
+        assert!(!job.mark_cancelled());
+
+        // Attempts still running are neither allowed to commit nor counted
+        let report = TaskReport {
+            worker_id: id,
+            job_id: "job1".to_string(),
+            task_name: "job1-0.txt".to_string(),
+            is_map: true,
+            outcome: TaskOutcome::Succeeded as i32,
+            ..TaskReport::default()
+        };
+        let response = tasks.report_task(Request::new(report)).await.unwrap();
+        assert!(!response.into_inner().commit);
+        let task = TaskRef {
+            name: "job1-0.txt".to_string(),
+            is_map: true,
+        };
+        let state = job.scheduler.lock().unwrap().state(&task);
+        assert_eq!(state, Some(TaskState::InProgress));
+    }
+
+    #[tokio::test]
+    async fn test_remove_output() {
+        let tasks = TaskService::new(connect().await, true);
+        let dir = env::temp_dir().join(format!("mrlite-remove-{}", process::id()));
+        let mut job = add_job(&tasks, "job1", &[TaskState::Completed]);
+        job.n_reduce = 2;
+        job.output_dir = dir.clone();
+        fs::create_dir_all(&dir).unwrap();
+        for name in ["out-0", "out-1", "skipped-map-0", "notes.txt"] {
+            fs::write(dir.join(name), name).unwrap();
+        }
+
+        // Only the job's own files are removed, and its directory is kept while others remain
+        job.remove_output();
+        let left: Vec<String> = fs::read_dir(&dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+            .collect();
+        assert_eq!(left, ["notes.txt"]);
+
+        fs::remove_file(dir.join("notes.txt")).unwrap();
+        job.remove_output();
+        assert!(!dir.exists());
+    }
+
+    #[tokio::test]
+    async fn test_drain() {
+        let tasks = TaskService::new(connect().await, false);
+        let job = add_job(&tasks, "running", &[TaskState::InProgress]);
+        let id = register(&tasks);
+        job.map_outputs.lock().unwrap().insert(
+            0,
+            MapOutput {
+                worker_id: id,
+                addr: "[::1]:1".to_string(),
+            },
+        );
+        tasks.workers.lock().unwrap().drain(id);
+
+        // A drained worker is told to exit, and the map output it held is forgotten
+        let reply = tasks
+            .send_task(Request::new(TaskRequest { worker_id: id }))
+            .await
+            .unwrap()
+            .into_inner();
+        match reply.action {
+            Some(Action::Exit(exit)) => assert!(exit.drained),
+            action => panic!("expected an exit, got {action:?}"),
+        }
+        assert!(!tasks.is_registered(id));
+        assert!(job.map_outputs.lock().unwrap().get(0).is_none());
+    }
+}
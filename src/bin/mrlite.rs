@@ -0,0 +1,700 @@
+use std::env;
+use std::error::Error;
+use std::io::{self, IsTerminal, Write};
+use std::path::{Path, PathBuf};
+use std::process::{exit, Child, Command};
+use std::str::FromStr;
+use std::thread;
+use std::time::Duration;
+
+use admin::admin_client::AdminClient;
+use admin::job_event::Event;
+use admin::{
+    CancelJobRequest, GetJobStatusRequest, JobState, JobStatus, ListJobsRequest, PhaseStatus,
+    SubmitJobRequest, TaskState, WatchJobRequest,
+};
+use colored::{ColoredString, Colorize};
+use mrlite::job::{Job, MasterArgs};
+use mrlite::local;
+use mrlite::scheduler::PhaseProgress;
+use mrlite::transport::{self, BearerToken, ClientTls, ListenAddr};
+use tonic::codegen::InterceptedService;
+use tonic::transport::Channel;
+
+pub mod admin {
+    tonic::include_proto!("admin");
+}
+
+pub mod tasks {
+    tonic::include_proto!("tasks");
+}
+
+static USAGE: &str = "usage:
+    mrlite run [--workers <n>] [--tls-ca <pem>] [<server option>...] [--job-id <id>]
+               [--if-exists refuse|resume|overwrite] [<job option>...] <n_map> <n_reduce> <filename>...
+    mrlite run [--workers <n>] [--tls-ca <pem>] [<server option>...] --resume <job-id>
+    mrlite local [--threads <n>] <n_map> <n_reduce> <filename>...
+    mrlite status [<master option>...] [<job-id>]
+    mrlite submit [<master option>...] [--job-id <id>] [--name <name>] [--output-dir <dir>]
+                  [<job option>...] <n_map> <n_reduce> <filename>...
+    mrlite watch [<master option>...] <job-id>
+    mrlite cancel [<master option>...] <job-id>
+
+server options:
+    --listen <addr>          address the master serves workers on: host:port (default
+                             [::1]:50051), or unix:<path> for a Unix domain socket only the
+                             current user can connect to
+    --tls-cert <pem>         serve workers over TLS with this certificate, which must be
+    --tls-key <pem>          issued to localhost, and its key. Workers verify it with the
+                             certificate authority in --tls-ca.
+
+master options, for status, submit, watch and cancel:
+    --master <url>           URL of the master, as given to workers (default
+                             http://[::1]:50051)
+    --tls-ca <pem>           verify a master serving TLS with this certificate authority
+    --tls-domain <name>      name the master's certificate is issued to, if not its host
+
+job options:
+    --max-attempts <n>       attempts a task is given before the job is aborted (default 4)
+    --max-user-failures <n>  map/reduce function failures a task is allowed (default 2)
+    --skip-bad-records       skip records that repeatedly crash the map/reduce function
+    --backup-threshold <f>   back up running tasks once this fraction of a phase is left
+                             (default 0.1, 0 disables backups)
+
+--job-id names the job, which is otherwise given a fresh id. --if-exists decides what
+happens when a job with that id was already started: refuse to run (default), resume
+it, or discard its state and start over. Give each job its own port or socket to run several
+side by side. If MRLITE_TOKEN is set, workers must present it to join the job.
+
+status lists the jobs a master is running, or shows the state of every task and worker of
+one of them. submit runs a job on a master started with `master --serve` and prints its id.
+Its input files are resolved relative to the master's working directory, and its output is
+written to --output-dir there, by default a directory named after the job, which must be
+new or empty. watch follows a job until it finishes, exiting with an error if it was
+aborted or cancelled. cancel stops a job for good, removing its output.";
+static SUPERVISE_INTERVAL: Duration = Duration::from_millis(500);
+static MAX_RESTARTS: u32 = 5;
+// Width of the progress bar of each phase watched
+static BAR_WIDTH: i64 = 30;
+
+// A worker process managed by the supervisor. `child` is None once the worker has exited
+// cleanly or has crashed too many times to be restarted.
+struct WorkerSlot {
+    child: Option<Child>,
+    restarts: u32,
+}
+
+// Returns the path of a binary built alongside this one
+fn sibling_bin(name: &str) -> std::io::Result<PathBuf> {
+    Ok(env::current_exe()?.with_file_name(name))
+}
+
+// Kills a child process, ignoring failures from processes that have already exited
+fn stop(child: &mut Child) {
+    let _ = child.kill();
+    let _ = child.wait();
+}
+
+// Launches a master and `n_workers` workers on this machine, restarting workers that crash,
+// and returns once the master shuts down after the job completes. Workers are started with
+// `worker_args`, which tell them how to connect to the master.
+fn run(
+    n_workers: usize,
+    job_args: &[String],
+    worker_args: &[String],
+) -> Result<(), Box<dyn Error>> {
+    let worker_bin = sibling_bin("worker")?;
+    let spawn_worker = || Command::new(&worker_bin).args(worker_args).spawn();
+    let mut master = Command::new(sibling_bin("master")?)
+        .args(job_args)
+        .spawn()?;
+    println!("INFO: Started master process {}.", master.id());
+
+    let mut slots = Vec::new();
+    for _ in 0..n_workers {
+        let child = spawn_worker()?;
+        println!("INFO: Started worker process {}.", child.id());
+        slots.push(WorkerSlot {
+            child: Some(child),
+            restarts: 0,
+        });
+    }
+
+    loop {
+        if let Some(status) = master.try_wait()? {
+            for child in slots.iter_mut().filter_map(|slot| slot.child.as_mut()) {
+                stop(child);
+            }
+            if !status.success() {
+                return Err(format!("master exited with {status}").into());
+            }
+            return Ok(());
+        }
+
+        for slot in slots.iter_mut() {
+            let Some(child) = slot.child.as_mut() else {
+                continue;
+            };
+            match child.try_wait()? {
+                None => {}
+                // Workers exit cleanly once there are no tasks left to hand out
+                Some(status) if status.success() => slot.child = None,
+                Some(status) if slot.restarts == MAX_RESTARTS => {
+                    eprintln!(
+                        "ERROR: Worker {} exited with {status}, giving up after {MAX_RESTARTS} restarts.",
+                        child.id()
+                    );
+                    slot.child = None;
+                }
+                Some(status) => {
+                    eprintln!(
+                        "WARN: Worker {} exited with {status}, restarting.",
+                        child.id()
+                    );
+                    slot.restarts += 1;
+                    slot.child = Some(spawn_worker()?);
+                }
+            }
+        }
+
+        // Without any live workers, tasks held by crashed workers can never finish
+        if slots.iter().all(|slot| slot.child.is_none())
+            && slots.iter().any(|slot| slot.restarts == MAX_RESTARTS)
+        {
+            stop(&mut master);
+            return Err("all workers exited before the job completed".into());
+        }
+
+        thread::sleep(SUPERVISE_INTERVAL);
+    }
+}
+
+impl From<PhaseStatus> for PhaseProgress {
+    fn from(status: PhaseStatus) -> PhaseProgress {
+        PhaseProgress {
+            idle: status.idle,
+            in_progress: status.in_progress,
+            completed: status.completed,
+        }
+    }
+}
+
+fn job_state_name(state: i32) -> &'static str {
+    match JobState::from_i32(state) {
+        Some(JobState::Running) => "running",
+        Some(JobState::Completed) => "completed",
+        Some(JobState::Aborted) => "aborted",
+        Some(JobState::Cancelled) => "cancelled",
+        None => "unknown",
+    }
+}
+
+fn task_state_name(state: i32) -> &'static str {
+    match TaskState::from_i32(state) {
+        Some(TaskState::Idle) => "idle",
+        Some(TaskState::InProgress) => "in progress",
+        Some(TaskState::Completed) => "completed",
+        None => "unknown",
+    }
+}
+
+fn phase_progress(status: Option<PhaseStatus>) -> PhaseProgress {
+    status.map(PhaseProgress::from).unwrap_or_default()
+}
+
+// Channel to a master presenting the token from the environment, if any
+type AdminChannel = InterceptedService<Channel, BearerToken>;
+
+fn print_job_status(job: &JobStatus) {
+    if job.name.is_empty() {
+        println!("Job {}: {}", job.job_id, job_state_name(job.state));
+    } else {
+        println!(
+            "Job {} ({}): {}",
+            job.job_id,
+            job.name,
+            job_state_name(job.state)
+        );
+    }
+    if !job.reason.is_empty() {
+        println!("  {}", job.reason);
+    }
+    println!("map phase: {}", phase_progress(job.map.clone()));
+    println!("reduce phase: {}", phase_progress(job.reduce.clone()));
+
+    println!("\ntasks:");
+    for task in &job.tasks {
+        let phase = if task.is_map { "map" } else { "reduce" };
+        let mut line = format!(
+            "  {:<6} {:<24} {:<11} attempts: {}",
+            phase,
+            task.name,
+            task_state_name(task.state),
+            task.attempts
+        );
+        if !task.worker_ids.is_empty() {
+            let ids: Vec<String> = task.worker_ids.iter().map(u64::to_string).collect();
+            line += &format!(", running on worker {}", ids.join(", "));
+        }
+        if !task.last_error.is_empty() {
+            line += &format!(", last error: {}", task.last_error);
+        }
+        println!("{line}");
+    }
+
+    println!("\nworkers:");
+    for worker in &job.workers {
+        let task = if worker.task_name.is_empty() {
+            "idle".to_string()
+        } else {
+            let phase = if worker.is_map { "map" } else { "reduce" };
+            format!(
+                "running {} task {} of job {}",
+                phase, worker.task_name, worker.job_id
+            )
+        };
+        println!(
+            "  {:<4} process {} on {} (map output on {}): {}, {} tasks completed, last seen {:.1}s ago{}",
+            worker.worker_id,
+            worker.pid,
+            worker.hostname,
+            worker.shuffle_addr,
+            task,
+            worker.tasks_completed,
+            worker.last_seen_ms as f64 / 1000.0,
+            if worker.draining { ", draining" } else { "" }
+        );
+    }
+}
+
+// Progress bar of a phase: completed tasks in green, then tasks in progress in yellow
+fn progress_bar(progress: PhaseProgress) -> String {
+    let total = progress.total().max(1);
+    let completed = progress.completed * BAR_WIDTH / total;
+    let started = (progress.completed + progress.in_progress) * BAR_WIDTH / total;
+    format!(
+        "[{}{}{}] {}/{}",
+        "#".repeat(completed as usize).green(),
+        "-".repeat((started - completed) as usize).yellow(),
+        " ".repeat((BAR_WIDTH - started) as usize),
+        progress.completed,
+        progress.total()
+    )
+}
+
+fn task_label(name: &str, is_map: bool) -> String {
+    let phase = if is_map { "map" } else { "reduce" };
+    format!("{phase} task {name}")
+}
+
+fn describe_event(event: &Event) -> ColoredString {
+    match event {
+        Event::TaskAssigned(assigned) if assigned.backup => format!(
+            "Backup of {} assigned to worker {} (racing attempt {})",
+            task_label(&assigned.name, assigned.is_map),
+            assigned.worker_id,
+            assigned.attempt
+        )
+        .normal(),
+        Event::TaskAssigned(assigned) => format!(
+            "{} assigned to worker {} (attempt {})",
+            task_label(&assigned.name, assigned.is_map),
+            assigned.worker_id,
+            assigned.attempt
+        )
+        .normal(),
+        Event::TaskCompleted(completed) => format!(
+            "{} completed by worker {}",
+            task_label(&completed.name, completed.is_map),
+            completed.worker_id
+        )
+        .green(),
+        Event::TaskFailed(failed) => format!(
+            "{} failed on worker {}: {}",
+            task_label(&failed.name, failed.is_map),
+            failed.worker_id,
+            failed.error
+        )
+        .red(),
+        Event::TaskReassigned(reassigned) => format!(
+            "{} reassigned: {}",
+            task_label(&reassigned.name, reassigned.is_map),
+            reassigned.reason
+        )
+        .yellow(),
+        Event::PhaseChanged(changed) => {
+            let phase = if changed.is_map { "Map" } else { "Reduce" };
+            format!("{phase} phase started").bold()
+        }
+        Event::JobFinished(finished) if finished.state == JobState::Completed as i32 => {
+            "Job completed".green().bold()
+        }
+        Event::JobFinished(finished) if finished.state == JobState::Cancelled as i32 => {
+            "Job cancelled".yellow().bold()
+        }
+        Event::JobFinished(finished) => format!(
+            "Job {}: {}",
+            job_state_name(finished.state),
+            finished.reason
+        )
+        .red()
+        .bold(),
+    }
+}
+
+// Connects to the Admin service of the master at `url`
+async fn connect_admin(
+    url: &str,
+    tls: Option<&ClientTls>,
+) -> Result<AdminClient<AdminChannel>, Box<dyn Error>> {
+    let token = BearerToken::new(transport::token_from_env().as_deref())?;
+    let channel = transport::connect(url, tls).await?;
+    Ok(AdminClient::with_interceptor(channel, token))
+}
+
+// Prints the status of a job running on the master at `url`, or lists its jobs if no job id is
+// given
+async fn status(
+    url: &str,
+    tls: Option<&ClientTls>,
+    job_id: Option<&str>,
+) -> Result<(), Box<dyn Error>> {
+    let mut client = connect_admin(url, tls).await?;
+    match job_id {
+        Some(job_id) => {
+            let request = GetJobStatusRequest {
+                job_id: job_id.to_string(),
+            };
+            let job = client
+                .get_job_status(request)
+                .await
+                .map_err(|status| status.message().to_string())?
+                .into_inner();
+            print_job_status(&job);
+        }
+        None => {
+            let jobs = client
+                .list_jobs(ListJobsRequest {})
+                .await
+                .map_err(|status| status.message().to_string())?
+                .into_inner()
+                .jobs;
+            println!(
+                "{:<24} {:<16} {:<10} {:<28} reduce",
+                "job", "name", "state", "map"
+            );
+            for job in jobs {
+                println!(
+                    "{:<24} {:<16} {:<10} {:<28} {}",
+                    job.job_id,
+                    job.name,
+                    job_state_name(job.state),
+                    phase_progress(job.map).to_string(),
+                    phase_progress(job.reduce)
+                );
+            }
+        }
+    }
+    Ok(())
+}
+
+// Follows a job running on the master at `url` until it finishes, logging its events above the
+// progress of each phase, which is redrawn in place on a terminal. Returns true if the job
+// completed.
+async fn watch(url: &str, tls: Option<&ClientTls>, job_id: &str) -> Result<bool, Box<dyn Error>> {
+    let mut client = connect_admin(url, tls).await?;
+    let request = WatchJobRequest {
+        job_id: job_id.to_string(),
+    };
+    let mut events = client
+        .watch_job(request)
+        .await
+        .map_err(|status| status.message().to_string())?
+        .into_inner();
+    let live = io::stdout().is_terminal();
+    while let Some(job_event) = events
+        .message()
+        .await
+        .map_err(|status| status.message().to_string())?
+    {
+        let Some(event) = &job_event.event else {
+            return Err("the master sent an event this version does not know".into());
+        };
+        if live {
+            // Clears the progress line
+            print!("\r\x1b[2K");
+        }
+        println!("{}", describe_event(event));
+        let finished = match event {
+            Event::JobFinished(finished) => Some(finished.state == JobState::Completed as i32),
+            _ => None,
+        };
+        if live || finished.is_some() {
+            print!(
+                "map {}  reduce {}",
+                progress_bar(phase_progress(job_event.map)),
+                progress_bar(phase_progress(job_event.reduce))
+            );
+        }
+        if let Some(completed) = finished {
+            println!();
+            return Ok(completed);
+        }
+        io::stdout().flush()?;
+    }
+    Err("the master stopped sending events before the job finished".into())
+}
+
+// Cancels a job running on the master at `url`, returning false if it had already finished
+async fn cancel(url: &str, tls: Option<&ClientTls>, job_id: &str) -> Result<bool, Box<dyn Error>> {
+    let mut client = connect_admin(url, tls).await?;
+    let request = CancelJobRequest {
+        job_id: job_id.to_string(),
+    };
+    let response = client
+        .cancel_job(request)
+        .await
+        .map_err(|status| status.message().to_string())?;
+    Ok(response.into_inner().cancelled)
+}
+
+// Submits a job to the master at `url`, returning the id it was given
+async fn submit(
+    url: &str,
+    tls: Option<&ClientTls>,
+    request: SubmitJobRequest,
+) -> Result<String, Box<dyn Error>> {
+    let mut client = connect_admin(url, tls).await?;
+    let response = client
+        .submit_job(request)
+        .await
+        .map_err(|status| status.message().to_string())?;
+    Ok(response.into_inner().job_id)
+}
+
+// Parses the `[--master <url>] [--tls-ca <pem> [--tls-domain <name>]]` options of the commands
+// that talk to a running master, returning the master's URL, how to verify it, and the
+// arguments that follow the options
+fn parse_master_options(
+    mut args: &[String],
+) -> Result<(String, Option<ClientTls>, &[String]), String> {
+    let mut url = ListenAddr::from_str(MasterArgs::DEFAULT_LISTEN)?.url();
+    let (mut tls_ca, mut tls_domain) = (None, None);
+    loop {
+        match args {
+            [flag, value, rest @ ..] if flag == "--master" => url = value.clone(),
+            [flag, value, rest @ ..] if flag == "--tls-ca" => tls_ca = Some(PathBuf::from(value)),
+            [flag, value, rest @ ..] if flag == "--tls-domain" => tls_domain = Some(value.clone()),
+            _ => break,
+        }
+        args = &args[2..];
+    }
+    let tls = tls_ca.map(|ca_cert| ClientTls {
+        ca_cert,
+        domain: tls_domain,
+    });
+    Ok((url, tls, args))
+}
+
+// Parses the `[<master option>...] <job-id>` arguments of the commands acting on one job
+fn parse_job_command(args: &[String]) -> Result<(String, Option<ClientTls>, &str), String> {
+    match parse_master_options(args)? {
+        (url, tls, [job_id]) if !job_id.starts_with("--") => Ok((url, tls, job_id)),
+        _ => Err("expected a job id".to_string()),
+    }
+}
+
+// Parses the `[--job-id <id>] [--name <name>] [--output-dir <dir>] <job>` arguments of the submit
+// command, where `<job>` is as accepted by `Job::from_args`
+fn parse_submit_args(mut args: &[String]) -> Result<SubmitJobRequest, String> {
+    let mut request = SubmitJobRequest::default();
+    loop {
+        match args {
+            [flag, value, ..] if flag == "--job-id" => request.job_id = value.clone(),
+            [flag, value, ..] if flag == "--name" => request.name = value.clone(),
+            [flag, value, ..] if flag == "--output-dir" => request.output_dir = value.clone(),
+            _ => break,
+        }
+        args = &args[2..];
+    }
+    let job = Job::from_args(args)?;
+    request.n_map = job.n_map;
+    request.n_reduce = job.n_reduce;
+    request.inputs = job.inputs;
+    request.options = Some(tasks::JobOptions {
+        max_attempts: job.options.max_attempts,
+        max_user_failures: job.options.max_user_failures,
+        skip_bad_records: job.options.skip_bad_records,
+        backup_threshold: job.options.backup_threshold,
+    });
+    Ok(request)
+}
+
+// Splits an optional `<flag> <n>` parallelism option off the front of `args`, defaulting to
+// the number of CPUs
+fn parse_parallelism<'a>(args: &'a [String], flags: &[&str]) -> (usize, &'a [String]) {
+    match args.first() {
+        Some(flag) if flags.contains(&flag.as_str()) => {
+            // Without a worker or thread, nothing would ever run the job
+            let n = args
+                .get(1)
+                .and_then(|n| usize::from_str(n).ok())
+                .filter(|n| *n > 0)
+                .unwrap_or_else(|| {
+                    eprintln!("ERROR: {flag} expects a positive number.\n{USAGE}");
+                    exit(2)
+                });
+            (n, &args[2..])
+        }
+        _ => (thread::available_parallelism().map_or(1, |n| n.get()), args),
+    }
+}
+
+// Takes the value of an option that may appear anywhere among the options of a command out of
+// its arguments, returning the value and the remaining arguments
+fn take_option(args: &[String], flag: &str) -> (Option<String>, Vec<String>) {
+    let Some(i) = args.iter().position(|arg| arg == flag) else {
+        return (None, args.to_vec());
+    };
+    let Some(value) = args.get(i + 1) else {
+        eprintln!("ERROR: {flag} expects a value.\n{USAGE}");
+        exit(2)
+    };
+    let mut rest = args[..i].to_vec();
+    rest.extend_from_slice(&args[i + 2..]);
+    (Some(value.clone()), rest)
+}
+
+// Parses the `[<job option>...] <n_map> <n_reduce> <filename>...` arguments of a local job
+fn parse_job(args: &[String]) -> Job {
+    Job::from_args(args).unwrap_or_else(|err| {
+        eprintln!("ERROR: {err}\n{USAGE}");
+        exit(2)
+    })
+}
+
+fn main() {
+    let args: Vec<String> = env::args().collect();
+
+    match args.get(1).map(String::as_str) {
+        Some("run") => {
+            let (n_workers, job_args) = parse_parallelism(&args[2..], &["--workers", "-w"]);
+            let (tls_ca, job_args) = take_option(job_args, "--tls-ca");
+            let master_args = MasterArgs::from_args(&job_args).unwrap_or_else(|err| {
+                eprintln!("ERROR: {err}\n{USAGE}");
+                exit(2)
+            });
+
+            let mut worker_args = vec!["--master".to_string(), master_args.master_url()];
+            match (&master_args.tls, tls_ca) {
+                // Workers connect to the master on this machine, which the certificate of a
+                // master serving TLS must be issued to
+                (Some(_), Some(ca)) => worker_args.extend([
+                    "--tls-ca".to_string(),
+                    ca,
+                    "--tls-domain".to_string(),
+                    "localhost".to_string(),
+                ]),
+                (Some(_), None) => {
+                    eprintln!("ERROR: --tls-ca is needed for workers to verify the master's certificate\n{USAGE}");
+                    exit(2)
+                }
+                (None, _) => {}
+            }
+
+            run(n_workers, &job_args, &worker_args).unwrap_or_else(|err| {
+                eprintln!("ERROR: Job failed: {err}");
+                exit(1)
+            });
+            println!("INFO: MapReduce is complete.");
+        }
+        Some("local") => {
+            let (n_threads, job_args) = parse_parallelism(&args[2..], &["--threads", "-t"]);
+            // Local runs neither retry nor skip records
+            if job_args.first().is_some_and(|arg| arg.starts_with("--")) {
+                eprintln!("ERROR: local runs take no job options\n{USAGE}");
+                exit(2)
+            }
+            let job = parse_job(job_args);
+
+            local::run(&job, Path::new("."), n_threads).unwrap_or_else(|err| {
+                eprintln!("ERROR: Job failed: {err}");
+                exit(1)
+            });
+            println!("INFO: MapReduce is complete.");
+        }
+        Some("status") => {
+            let (url, tls, job_id) = parse_master_options(&args[2..])
+                .and_then(|(url, tls, rest)| match rest {
+                    [] => Ok((url, tls, None)),
+                    [job_id] if !job_id.starts_with("--") => Ok((url, tls, Some(job_id.as_str()))),
+                    _ => Err("expected at most one job id".to_string()),
+                })
+                .unwrap_or_else(|err| {
+                    eprintln!("ERROR: {err}\n{USAGE}");
+                    exit(2)
+                });
+            let runtime = tokio::runtime::Runtime::new().unwrap();
+            runtime
+                .block_on(status(&url, tls.as_ref(), job_id))
+                .unwrap_or_else(|err| {
+                    eprintln!("ERROR: Could not get the status from {url}: {err}");
+                    exit(1)
+                });
+        }
+        Some("submit") => {
+            let (url, tls, request) = parse_master_options(&args[2..])
+                .and_then(|(url, tls, rest)| Ok((url, tls, parse_submit_args(rest)?)))
+                .unwrap_or_else(|err| {
+                    eprintln!("ERROR: {err}\n{USAGE}");
+                    exit(2)
+                });
+            let runtime = tokio::runtime::Runtime::new().unwrap();
+            let job_id = runtime
+                .block_on(submit(&url, tls.as_ref(), request))
+                .unwrap_or_else(|err| {
+                    eprintln!("ERROR: Could not submit the job to {url}: {err}");
+                    exit(1)
+                });
+            println!("{job_id}");
+        }
+        Some("watch") => {
+            let (url, tls, job_id) = parse_job_command(&args[2..]).unwrap_or_else(|err| {
+                eprintln!("ERROR: {err}\n{USAGE}");
+                exit(2)
+            });
+            let runtime = tokio::runtime::Runtime::new().unwrap();
+            let completed = runtime
+                .block_on(watch(&url, tls.as_ref(), job_id))
+                .unwrap_or_else(|err| {
+                    eprintln!("ERROR: Could not watch job {job_id} on {url}: {err}");
+                    exit(1)
+                });
+            if !completed {
+                exit(1);
+            }
+        }
+        Some("cancel") => {
+            let (url, tls, job_id) = parse_job_command(&args[2..]).unwrap_or_else(|err| {
+                eprintln!("ERROR: {err}\n{USAGE}");
+                exit(2)
+            });
+            let runtime = tokio::runtime::Runtime::new().unwrap();
+            let cancelled = runtime
+                .block_on(cancel(&url, tls.as_ref(), job_id))
+                .unwrap_or_else(|err| {
+                    eprintln!("ERROR: Could not cancel job {job_id} on {url}: {err}");
+                    exit(1)
+                });
+            if !cancelled {
+                eprintln!("ERROR: Job {job_id} had already finished.");
+                exit(1);
+            }
+            println!("INFO: Cancelled job {job_id}.");
+        }
+        _ => {
+            eprintln!("{USAGE}");
+            exit(2);
+        }
+    }
+}
@@ -1,193 +1,789 @@
-use mongodb::{options::ClientOptions, Client};
-use serde::Serialize;
-use std::collections::hash_map::DefaultHasher;
-use std::fs::File;
-use std::hash::{Hash, Hasher};
-use std::io::prelude::*;
-use std::io::{BufReader, LineWriter, Read};
-use std::path::Path;
+use mrlite::job::{self, TaskError};
+use mrlite::location::Location;
+use mrlite::transport::{self, BearerToken, ClientTls};
+use mrlite::version;
+use std::collections::{HashMap, HashSet};
+use std::env;
+use std::fmt;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
 use std::process;
-use std::process::exit;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tasks::shuffle_client::ShuffleClient;
+use tasks::shuffle_server::{Shuffle, ShuffleServer};
 use tasks::task_client::TaskClient;
-use tasks::TaskRequest;
+use tasks::task_response::Action;
+use tasks::{
+    FetchPartitionRequest, HeartbeatRequest, JobInfo, MapTask, PartitionChunk, ReduceTask,
+    RegisterRequest, TaskOutcome, TaskReport, TaskRequest,
+};
+use tokio::net::TcpListener;
+use tokio::sync::{mpsc, oneshot};
+use tokio::task::JoinHandle;
+use tokio_stream::wrappers::{ReceiverStream, TcpListenerStream};
+use tonic::codegen::InterceptedService;
+use tonic::transport::{Channel, Server};
+use tonic::{Code, Request, Response, Status};
 
 pub mod tasks {
     tonic::include_proto!("tasks");
 }
 
+static HEARTBEAT_INTERVAL: Duration = Duration::from_secs(2);
+static MASTER_ADDR: &str = "http://[::1]:50051";
+static SHUFFLE_LISTEN: &str = "[::1]:0";
+// Size of the pieces map output is streamed to reducers in
+static CHUNK_SIZE: usize = 64 * 1024;
+static USAGE: &str = "usage: worker [--master <url>] [--tls-ca <pem> [--tls-domain <name>]]
+              [--shuffle-listen <addr>] [--data-dir <dir>]
+
+--master is the URL of the master, http://<host:port>, https://<host:port> or unix:<path>
+(default http://[::1]:50051). Masters serving TLS are verified with the certificate authority
+in --tls-ca, against the name in --tls-domain if their certificate is not issued to the host
+in the URL. If the master requires a token, it is read from the MRLITE_TOKEN environment
+variable. Map output is kept in
+--data-dir (default a fresh directory under the system temporary directory) and served to
+other workers on --shuffle-listen (default [::1]:0, any free port on the loopback address).";
+
+// Channel presenting the job's token, if any, with every request
+type AuthChannel = InterceptedService<Channel, BearerToken>;
+
+// Shuffle token of each job, by job id
+type ShuffleTokens = Arc<Mutex<HashMap<String, String>>>;
+
+// Progress of a worker, reported to the master in heartbeats
+#[derive(Debug, Default)]
+struct Progress {
+    worker_id: u64,    // changes if the worker has to register again
+    job_id: String,    // job of the task in progress, empty while the worker is idle
+    task_name: String, // empty while the worker is idle
+    is_map: bool,
+    tasks_completed: u64,
+    // Stops the task in progress, taken when the master asks for it to be aborted
+    abort: Option<oneshot::Sender<()>>,
+}
+
+// A map or reduce task handed out by the master
+#[derive(Debug)]
+struct Assignment {
+    task_name: String,
+    is_map: bool,
+    tasknum: i64,
+    // The input file of a map task, or the intermediate files of a reduce task
+    inputs: Vec<String>,
+    job: JobInfo,
+    skip_records: Vec<i64>,
+}
+impl From<MapTask> for Assignment {
+    fn from(task: MapTask) -> Assignment {
+        Assignment {
+            task_name: task.task_name,
+            is_map: true,
+            tasknum: task.tasknum as i64,
+            inputs: vec![task.input],
+            job: task.job.unwrap_or_default(),
+            skip_records: task.skip_records,
+        }
+    }
+}
+impl From<ReduceTask> for Assignment {
+    fn from(task: ReduceTask) -> Assignment {
+        Assignment {
+            task_name: task.task_name,
+            is_map: false,
+            tasknum: task.tasknum as i64,
+            inputs: task.intermediate_locations,
+            job: task.job.unwrap_or_default(),
+            skip_records: task.skip_records,
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct Worker {
-    id: u32,
+    id: u64,
     done: bool,
+    progress: Arc<Mutex<Progress>>,
+    // Connection to the master, kept for the worker's whole lifetime
+    client: TaskClient<AuthChannel>,
+    // Directory holding the map output this worker serves, in a subdirectory per job
+    data_dir: PathBuf,
+    // Address other workers fetch that map output from, as given to the master
+    shuffle_addr: String,
+    // Jobs this worker has run tasks of, whose map output is removed once they finish
+    job_ids: HashSet<String>,
+    // Shuffle tokens of the jobs this worker holds map output of, shared with its shuffle
+    // service, which requires them of the reduce tasks fetching it
+    shuffle_tokens: ShuffleTokens,
 }
 impl Worker {
-    pub fn new(id: u32, done: bool) -> Worker {
-        Worker { id, done }
+    pub fn new(
+        id: u64,
+        done: bool,
+        client: TaskClient<AuthChannel>,
+        data_dir: PathBuf,
+        shuffle_addr: String,
+        shuffle_tokens: ShuffleTokens,
+    ) -> Worker {
+        let progress = Progress {
+            worker_id: id,
+            ..Progress::default()
+        };
+        Worker {
+            id,
+            done,
+            progress: Arc::new(Mutex::new(progress)),
+            client,
+            data_dir,
+            shuffle_addr,
+            job_ids: HashSet::new(),
+            shuffle_tokens,
+        }
     }
-    pub fn get_id(&self) -> u32 {
+    pub fn get_id(&self) -> u64 {
         self.id
     }
     pub fn done(&self) -> bool {
         self.done
     }
 
-    pub async fn boot(&self) -> Result<(), Box<dyn std::error::Error>> {
-        // Retrieve some information about the current master state that the workers
-        // share. This seems kinda expensive since we are initializing a new
-        // database connection per request we serve - it would be nice if these
-        // values could persist per worker session.
-        //
-        // Can also considering making n_map and n_reduce part of the RPC response object
-        let client_options = ClientOptions::parse("mongodb://localhost:27017")
-            .await
-            .unwrap_or_else(|err| {
-                eprintln!("ERROR: could not parse address: {err}");
-                exit(1)
-            });
-        let db_client = Client::with_options(client_options).unwrap_or_else(|err| {
-            eprintln!("ERROR: could not initialize database client: {err}");
-            exit(1)
-        });
-        let db_name = "mapreduce";
-        let coll_name = "state";
-        let record_name = "current_master_state";
+    // Starts reporting this worker's progress to the master in the background
+    pub fn start_heartbeats(&self) {
+        tokio::spawn(send_heartbeats(self.client.clone(), self.progress.clone()));
+    }
 
-        let n_map = mongo_utils::get_val(&db_client, db_name, coll_name, record_name, "n_map")
-            .await
-            .unwrap();
-        let n_reduce =
-            mongo_utils::get_val(&db_client, db_name, coll_name, record_name, "n_reduce")
-                .await
-                .unwrap();
+    // Registers again after the master has forgotten this worker, e.g. because its heartbeats
+    // were held up for longer than the master waits for them. By then the master has handed
+    // its task to another worker, and stopped sending reduce tasks to its map output.
+    async fn rejoin(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        let mut client = self.client.clone();
+        let id = register(&mut client, &self.shuffle_addr).await?;
+        eprintln!(
+            "WARN: Worker {} was no longer registered with the master, registered again as worker {}.",
+            self.id, id
+        );
+        self.id = id;
+        self.progress.lock().unwrap().worker_id = id;
+        Ok(())
+    }
 
-        let mut client = TaskClient::connect("http://[::1]:50051").await?;
+    // Records the task in progress, returning a receiver that fires if the master asks for it
+    // to be aborted
+    fn start_task(&self, job_id: &str, task_name: &str, is_map: bool) -> oneshot::Receiver<()> {
+        let (abort, aborted) = oneshot::channel();
+        let mut progress = self.progress.lock().unwrap();
+        progress.job_id = job_id.to_string();
+        progress.task_name = task_name.to_string();
+        progress.is_map = is_map;
+        progress.abort = Some(abort);
+        aborted
+    }
+
+    // Discards an attempt of a task the master asked to abort. A map or reduce function cannot
+    // be interrupted, so the attempt directory is only removed once `running` has returned, as
+    // it may still be writing to it.
+    async fn abort_task(
+        &self,
+        task: &Assignment,
+        attempt_dir: &Path,
+        fetch_dir: &Path,
+        running: Option<JoinHandle<Result<(), TaskError>>>,
+    ) {
+        println!(
+            "INFO: Worker {} aborting task {}, job {} is no longer running.",
+            self.id, task.task_name, task.job.job_id
+        );
+        if let Some(running) = running {
+            let _ = running.await;
+        }
+        let _ = fs::remove_dir_all(attempt_dir);
+        let _ = fs::remove_dir_all(fetch_dir);
+        self.finish_task(false);
+    }
+
+    fn finish_task(&self, succeeded: bool) {
+        let mut progress = self.progress.lock().unwrap();
+        progress.job_id.clear();
+        progress.task_name.clear();
+        progress.abort = None;
+        if succeeded {
+            progress.tasks_completed += 1;
+        }
+    }
+
+    // Requests and runs a single task. Sets the done flag once the master reports that
+    // there is no work left.
+    pub async fn boot(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        // Clones share the worker's connection to the master
+        let mut client = self.client.clone();
         // create new request
-        let request = tonic::Request::new(TaskRequest { id: process::id() });
-        let response = client.send_task(request).await?;
+        let request = tonic::Request::new(TaskRequest { worker_id: self.id });
+        let response = match client.send_task(request).await {
+            Ok(response) => response.into_inner(),
+            Err(status) if status.code() == Code::FailedPrecondition => {
+                self.rejoin().await?;
+                return Ok(());
+            }
+            Err(status) => return Err(status.into()),
+        };
+
+        // No reduce task will fetch the map output of jobs that have finished
+        for job_id in &response.finished_jobs {
+            if self.job_ids.remove(job_id) {
+                let _ = fs::remove_dir_all(self.data_dir.join(job_id));
+                self.shuffle_tokens.lock().unwrap().remove(job_id);
+            }
+        }
+
+        // The response carries everything needed to run the task
+        let task = match response.action {
+            Some(Action::MapTask(map_task)) => Assignment::from(map_task),
+            Some(Action::ReduceTask(reduce_task)) => Assignment::from(reduce_task),
+            Some(Action::Wait(wait)) => {
+                println!("DEBUG: Worker {} was told to wait.", self.id);
+                tokio::time::sleep(Duration::from_millis(wait.retry_after_ms)).await;
+                return Ok(());
+            }
+            Some(Action::Exit(exit)) => {
+                if exit.drained {
+                    println!("INFO: Exiting: {}.", exit.reason);
+                } else if !exit.completed {
+                    eprintln!("ERROR: Job aborted: {}", exit.reason);
+                }
+                // No reduce task will fetch this worker's map output any more
+                for job_id in self.job_ids.drain() {
+                    let _ = fs::remove_dir_all(self.data_dir.join(job_id));
+                }
+                self.shuffle_tokens.lock().unwrap().clear();
+                self.done = true;
+                return Ok(());
+            }
+            None => return Err("master sent a task response without an action".into()),
+        };
+        // Responses are not logged whole, as they carry the job's shuffle token
+        println!(
+            "DEBUG: Worker {} got {} task {} of job {}.",
+            self.id,
+            if task.is_map { "map" } else { "reduce" },
+            task.task_name,
+            task.job.job_id
+        );
+        let n_reduce = task.job.n_reduce;
+        let is_map = task.is_map;
+        let task_name = task.task_name.clone();
+        let tasknum = task.tasknum;
+        let skip_mode = task
+            .job
+            .options
+            .as_ref()
+            .is_some_and(|options| options.skip_bad_records);
+        let skip: HashSet<i64> = task.skip_records.iter().copied().collect();
+        let output_dir = PathBuf::from(&task.job.output_dir);
+        // Map output stays on this worker, to be served to reduce tasks
+        let shuffle_dir = self.data_dir.join(&task.job.job_id);
+        self.job_ids.insert(task.job.job_id.clone());
+        self.shuffle_tokens
+            .lock()
+            .unwrap()
+            .insert(task.job.job_id.clone(), task.job.shuffle_token.clone());
+        let shuffle_token = BearerToken::new(Some(&task.job.shuffle_token))?;
+        let aborted = self.start_task(&task.job.job_id, &task_name, is_map);
 
-        println!("RESPONSE={:?}", response);
+        // Output is written to a directory private to this attempt, and only moved into place
+        // if the master lets this attempt commit
+        let attempt_name = format!(".attempt-{}", self.id);
+        let attempt_dir = if is_map {
+            shuffle_dir.join(&attempt_name)
+        } else {
+            output_dir.join(&attempt_name)
+        };
+        let _ = fs::remove_dir_all(&attempt_dir);
+        fs::create_dir_all(&attempt_dir)?;
 
-        let is_map = response.get_ref().is_map;
-        let task_name = &response.get_ref().task_name;
-        let reduce_tasknum = calculate_hash(task_name) % n_reduce as u64;
+        // Reduce tasks fetch their inputs from other workers into a directory of their own
+        let fetch_dir = shuffle_dir.join(format!(".fetch-{}", self.id));
+        if !is_map {
+            println!("DEBUG: Worker {} received reduce task.", self.id);
+            let _ = fs::remove_dir_all(&fetch_dir);
+            fs::create_dir_all(&fetch_dir)?;
+        }
+
+        // Fires once if the master asks for the task to be aborted, and never if the heartbeats
+        // stopped without asking
+        let aborted = async {
+            if aborted.await.is_err() {
+                std::future::pending::<()>().await;
+            }
+        };
+        tokio::pin!(aborted);
 
-        if is_map {
-            let tasknum = &response.get_ref().tasknum;
-            map_file(task_name, format!("map-{}-{}", tasknum, reduce_tasknum))
-                .expect("ERROR: Could not complete map task.");
-            mongo_utils::update_done(&db_client, "mapreduce", "map_tasks", task_name, true).await;
+        // Inputs being fetched are abandoned if the task is aborted
+        let fetched = if is_map {
+            Ok(Vec::new())
         } else {
-            println!("DEBUG: Client received reduce task.");
-            for i in 0..n_map {
-                let intermediate_filename = format!("map-{}-{}", i, task_name);
-                let path = Path::new(&intermediate_filename);
-
-                if path.exists() {
-                    let f = File::open(intermediate_filename)
-                        .expect("ERROR: File should open read only");
-                    let json: serde_json::Value =
-                        serde_json::from_reader(f).expect("file should be proper JSON");
-                    let mut kv_pairs = Vec::new();
-
-                    for j in json.as_array().unwrap() {
-                        kv_pairs.push(KVPair {
-                            key: j["key"].as_str().unwrap().to_string(),
-                            val: j["val"].as_u64().unwrap(),
-                        });
+            let fetch = fetch_inputs(&task.inputs, &task.job.job_id, &fetch_dir, &shuffle_token);
+            tokio::select! {
+                fetched = fetch => fetched,
+                () = &mut aborted => {
+                    self.abort_task(&task, &attempt_dir, &fetch_dir, None).await;
+                    return Ok(());
+                }
+            }
+        };
+
+        // Map and reduce functions block, so keep them off the threads driving heartbeats
+        let out_dir = attempt_dir.clone();
+        let filename = task.inputs.first().cloned().unwrap_or_default();
+        // Location of the map output a reduce task could not fetch, if any
+        let mut failed_location = String::new();
+        let result = match fetched {
+            Ok(inputs) => {
+                let mut running = tokio::task::spawn_blocking(move || {
+                    if is_map && skip_mode {
+                        job::map_file_skipping(&filename, &out_dir, tasknum, n_reduce, &skip)
+                    } else if is_map {
+                        Ok(job::map_file(&filename, &out_dir, tasknum, n_reduce)?)
+                    } else if skip_mode {
+                        job::reduce_partition_skipping(&inputs, &out_dir, tasknum, &skip)
+                    } else {
+                        Ok(job::reduce_partition(&inputs, &out_dir, tasknum)?)
                     }
-                    kv_pairs.sort();
-
-                    let len = kv_pairs.len();
-                    let file = File::create(format!("out-{}", task_name))?;
-                    let mut file = LineWriter::new(file);
-                    let mut i = 0;
-
-                    // Now that the key value pairs are sorted, we can easily coalesce adjacent
-                    // pairs if they are equivalent
-                    while i < len {
-                        let mut j = i + 1;
-                        while j < len && kv_pairs[j].key == kv_pairs[i].key {
-                            j += 1;
-                        }
-                        let mut vals = Vec::new();
-
-                        for k in i..j {
-                            vals.push(kv_pairs[k].val);
-                        }
-
-                        let count = reduce(kv_pairs[i].key.clone(), vals);
-                        file.write_all(format!("{} {}\n", kv_pairs[i].key, count).as_bytes())?;
-                        i = j;
+                });
+                tokio::select! {
+                    result = &mut running => result,
+                    () = &mut aborted => {
+                        self.abort_task(&task, &attempt_dir, &fetch_dir, Some(running)).await;
+                        return Ok(());
                     }
                 }
             }
+            Err(err) => {
+                failed_location = err.location.clone();
+                Ok(Err(TaskError::Io(io::Error::other(err.to_string()))))
+            }
+        };
+        let _ = fs::remove_dir_all(&fetch_dir);
+
+        // Panics come from the user map and reduce functions, and will most likely happen
+        // again if retried. Anything else is an environmental error worth retrying.
+        let (outcome, bad_record, error) = match result {
+            Ok(Ok(())) => (TaskOutcome::Succeeded, -1, String::new()),
+            Ok(Err(TaskError::Io(err))) if !failed_location.is_empty() => {
+                (TaskOutcome::FetchFailed, -1, err.to_string())
+            }
+            Ok(Err(TaskError::Io(err))) => (TaskOutcome::FailedRetryable, -1, err.to_string()),
+            Ok(Err(err @ TaskError::BadRecord { offset, .. })) => {
+                (TaskOutcome::FailedUserCode, offset, err.to_string())
+            }
+            Err(err) if err.is_panic() => (
+                TaskOutcome::FailedUserCode,
+                -1,
+                job::panic_message(err.into_panic()),
+            ),
+            Err(err) => (TaskOutcome::FailedRetryable, -1, err.to_string()),
+        };
+        if outcome != TaskOutcome::Succeeded {
+            eprintln!(
+                "ERROR: Worker {} failed task {}: {}",
+                self.id, task_name, error
+            );
+        }
+
+        let report = TaskReport {
+            error,
+            bad_record,
+            failed_location,
+            ..self.task_report(&task, outcome)
+        };
+        let commit = self.report(&mut client, report).await?;
+        let mut succeeded = false;
+        if commit {
+            match commit_attempt(&attempt_dir, &output_dir, &shuffle_dir) {
+                Ok(()) => {
+                    let report = self.task_report(&task, TaskOutcome::Committed);
+                    self.report(&mut client, report).await?;
+                    succeeded = true;
+                }
+                Err(err) => {
+                    eprintln!(
+                        "ERROR: Worker {} could not commit task {}: {}",
+                        self.id, task_name, err
+                    );
+                    let report = TaskReport {
+                        error: err.to_string(),
+                        ..self.task_report(&task, TaskOutcome::FailedRetryable)
+                    };
+                    self.report(&mut client, report).await?;
+                }
+            }
+        } else if outcome == TaskOutcome::Succeeded {
+            println!(
+                "INFO: Worker {} discarding output of task {}, another attempt committed first.",
+                self.id, task_name
+            );
         }
+
+        let _ = fs::remove_dir_all(&attempt_dir);
+        self.finish_task(succeeded);
         Ok(())
     }
+
+    // Report of a task attempt's outcome, without any details of a failure
+    fn task_report(&self, task: &Assignment, outcome: TaskOutcome) -> TaskReport {
+        TaskReport {
+            worker_id: self.id,
+            job_id: task.job.job_id.clone(),
+            task_name: task.task_name.clone(),
+            is_map: task.is_map,
+            outcome: outcome as i32,
+            error: String::new(),
+            bad_record: -1,
+            failed_location: String::new(),
+        }
+    }
+
+    // Reports the outcome of a task attempt to the master, returning whether the attempt
+    // should commit its output. A worker the master has forgotten registers again, and its
+    // attempt is discarded since the task has been handed to another worker.
+    async fn report(
+        &mut self,
+        client: &mut TaskClient<AuthChannel>,
+        report: TaskReport,
+    ) -> Result<bool, Box<dyn std::error::Error>> {
+        let report = tonic::Request::new(report);
+        match client.report_task(report).await {
+            Ok(response) => Ok(response.get_ref().commit),
+            Err(status) if status.code() == Code::FailedPrecondition => {
+                self.rejoin().await?;
+                Ok(false)
+            }
+            Err(status) => Err(status.into()),
+        }
+    }
 }
 
-#[derive(Serialize, Debug, Eq, Ord, PartialEq, PartialOrd)]
-pub struct KVPair {
-    key: String,
-    val: u64,
+// Moves every file written by a task attempt into place: intermediate files into the
+// directory this worker serves map output from, and anything else into the job's output
+// directory
+fn commit_attempt(attempt_dir: &Path, output_dir: &Path, shuffle_dir: &Path) -> io::Result<()> {
+    for entry in fs::read_dir(attempt_dir)? {
+        let entry = entry?;
+        let name = entry.file_name();
+        let dir = if name.to_string_lossy().starts_with("map-") {
+            shuffle_dir
+        } else {
+            output_dir
+        };
+        let target = dir.join(&name);
+        // The output directory may be on another filesystem, which files cannot be renamed to
+        if fs::rename(entry.path(), &target).is_err() {
+            fs::copy(entry.path(), &target)?;
+            fs::remove_file(entry.path())?;
+        }
+    }
+    Ok(())
 }
 
-// Open file, call map function on its contents, and write results to disk
-fn map_file(filepath: &str, intermediate_filename: String) -> std::io::Result<()> {
-    let file = File::open(filepath)?; // for error handling
-    let mut buf_reader = BufReader::new(file);
-    let mut contents = String::new();
+// A map output partition that a reduce task could not fetch
+#[derive(Debug)]
+struct FetchError {
+    location: String,
+    error: String,
+}
+impl fmt::Display for FetchError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "could not fetch {}: {}", self.location, self.error)
+    }
+}
 
-    buf_reader.read_to_string(&mut contents)?;
+// Makes the input files of a reduce task available locally, downloading the partitions held
+// by other workers into `dir`. Returns the paths of the input files, or the first location that
+// could not be fetched.
+async fn fetch_inputs(
+    locations: &[String],
+    job_id: &str,
+    dir: &Path,
+    token: &BearerToken,
+) -> Result<Vec<PathBuf>, FetchError> {
+    let mut clients: HashMap<String, ShuffleClient<AuthChannel>> = HashMap::new();
+    let mut inputs = Vec::new();
+    for location in locations {
+        let input = fetch_input(&mut clients, location, job_id, dir, token)
+            .await
+            .map_err(|err| FetchError {
+                location: location.clone(),
+                error: err.to_string(),
+            })?;
+        inputs.push(input);
+    }
+    Ok(inputs)
+}
 
-    let kv_pairs = map(&contents);
-    let mut intermediate_file = File::create(intermediate_filename)?;
-    let json = serde_json::to_string(&kv_pairs)?;
+// Fetches a single input file of a reduce task, reusing the connections in `clients`
+async fn fetch_input(
+    clients: &mut HashMap<String, ShuffleClient<AuthChannel>>,
+    location: &str,
+    job_id: &str,
+    dir: &Path,
+    token: &BearerToken,
+) -> Result<PathBuf, Box<dyn std::error::Error>> {
+    let (addr, map_tasknum, partition) = match location.parse::<Location>()? {
+        Location::File(path) => return Ok(path),
+        Location::Shuffle {
+            addr,
+            map_tasknum,
+            partition,
+        } => (addr, map_tasknum, partition),
+    };
 
-    intermediate_file.write_all(json.as_bytes())?;
+    let client = match clients.get_mut(&addr) {
+        Some(client) => client,
+        None => {
+            let channel = transport::connect(&format!("http://{addr}"), None).await?;
+            let client = ShuffleClient::with_interceptor(channel, token.clone());
+            clients.entry(addr).or_insert(client)
+        }
+    };
+    let request = FetchPartitionRequest {
+        job_id: job_id.to_string(),
+        map_tasknum,
+        partition,
+    };
+    let mut stream = client.fetch_partition(request).await?.into_inner();
+    let path = job::intermediate_filename(dir, map_tasknum, partition);
+    let mut file = fs::File::create(&path)?;
+    while let Some(chunk) = stream.message().await? {
+        file.write_all(&chunk.data)?;
+    }
+    Ok(path)
+}
 
-    Ok(())
+// Serves the map output held by this worker to reduce tasks running on other workers
+#[derive(Debug)]
+pub struct ShuffleService {
+    data_dir: PathBuf,
+    shuffle_tokens: ShuffleTokens,
 }
 
-// User defined map function goes here
-fn map(contents: &str) -> Vec<KVPair> {
-    let mut kv_pairs = Vec::new();
-    let mut iter = contents.split_whitespace();
-    loop {
-        let word = iter.next();
-        if word.is_none() {
-            break;
-        } else {
-            kv_pairs.push(KVPair {
-                key: word.unwrap().to_string(),
-                val: 1,
-            });
+#[tonic::async_trait]
+impl Shuffle for ShuffleService {
+    type FetchPartitionStream = ReceiverStream<Result<PartitionChunk, Status>>;
+
+    async fn fetch_partition(
+        &self,
+        request: Request<FetchPartitionRequest>,
+    ) -> Result<Response<Self::FetchPartitionStream>, Status> {
+        // Every job has a token of its own, so that workers never send the master's token over
+        // shuffle connections, which are not encrypted
+        let token = self
+            .shuffle_tokens
+            .lock()
+            .unwrap()
+            .get(&request.get_ref().job_id)
+            .cloned();
+        if !token.is_some_and(|token| transport::presents_token(&request, &token)) {
+            return Err(Status::unauthenticated("Missing or invalid shuffle token."));
         }
+        let request = request.into_inner();
+        if !job::valid_job_id(&request.job_id) {
+            return Err(Status::invalid_argument(format!(
+                "Invalid job id {}.",
+                request.job_id
+            )));
+        }
+        let path = job::intermediate_filename(
+            &self.data_dir.join(&request.job_id),
+            request.map_tasknum,
+            request.partition,
+        );
+        let mut file = fs::File::open(&path).map_err(|err| {
+            Status::not_found(format!("Could not open {}: {}", path.display(), err))
+        })?;
+
+        // Files are read on a blocking thread, and sent as the reducer is ready for them
+        let (sender, receiver) = mpsc::channel(4);
+        tokio::task::spawn_blocking(move || {
+            let mut buf = vec![0; CHUNK_SIZE];
+            loop {
+                let chunk = match file.read(&mut buf) {
+                    Ok(0) => return,
+                    Ok(n) => Ok(PartitionChunk {
+                        data: buf[..n].to_vec(),
+                    }),
+                    Err(err) => Err(Status::internal(err.to_string())),
+                };
+                let failed = chunk.is_err();
+                if sender.blocking_send(chunk).is_err() || failed {
+                    return;
+                }
+            }
+        });
+        Ok(Response::new(ReceiverStream::new(receiver)))
     }
-    kv_pairs
 }
 
-// User defined reduce function goes here
-fn reduce(_key: String, vals: Vec<u64>) -> u64 {
-    let mut total = 0;
-    for val in vals {
-        total += val;
+// Address other workers can reach this worker's shuffle service at, given the address it is
+// listening on
+fn advertised_addr(addr: SocketAddr) -> String {
+    if addr.ip().is_unspecified() {
+        format!("{}:{}", hostname(), addr.port())
+    } else {
+        addr.to_string()
+    }
+}
+
+// Name of the host this worker is running on
+fn hostname() -> String {
+    std::fs::read_to_string("/proc/sys/kernel/hostname")
+        .map(|name| name.trim().to_string())
+        .or_else(|_| std::env::var("HOSTNAME"))
+        .unwrap_or_else(|_| "unknown".to_string())
+}
+
+// Sends a heartbeat to the master every HEARTBEAT_INTERVAL for as long as the worker runs
+async fn send_heartbeats(mut client: TaskClient<AuthChannel>, progress: Arc<Mutex<Progress>>) {
+    loop {
+        let request = {
+            let progress = progress.lock().unwrap();
+            HeartbeatRequest {
+                worker_id: progress.worker_id,
+                job_id: progress.job_id.clone(),
+                task_name: progress.task_name.clone(),
+                is_map: progress.is_map,
+                tasks_completed: progress.tasks_completed,
+            }
+        };
+
+        let worker_id = request.worker_id;
+        let (job_id, task_name) = (request.job_id.clone(), request.task_name.clone());
+        match client.heartbeat(request).await {
+            Ok(response) if response.get_ref().abort_task => {
+                let mut progress = progress.lock().unwrap();
+                // The worker may have moved on to another task since
+                if progress.job_id == job_id && progress.task_name == task_name {
+                    if let Some(abort) = progress.abort.take() {
+                        let _ = abort.send(());
+                    }
+                }
+            }
+            Ok(_) => {}
+            Err(status) => eprintln!(
+                "WARN: Worker {} could not send heartbeat: {}",
+                worker_id,
+                status.message()
+            ),
+        }
+        tokio::time::sleep(HEARTBEAT_INTERVAL).await;
     }
-    total
 }
 
-fn calculate_hash<T: Hash>(t: &T) -> u64 {
-    let mut default_hasher = DefaultHasher::new();
-    t.hash(&mut default_hasher);
-    default_hasher.finish()
+// Joins the job, returning the worker id issued by the master. Fails if the master does not
+// support this worker's protocol version, or the other way around.
+async fn register(
+    client: &mut TaskClient<AuthChannel>,
+    shuffle_addr: &str,
+) -> Result<u64, Box<dyn std::error::Error>> {
+    let request = tonic::Request::new(RegisterRequest {
+        pid: process::id(),
+        hostname: hostname(),
+        protocol_version: version::PROTOCOL_VERSION,
+        build_version: version::BUILD_VERSION.to_string(),
+        shuffle_addr: shuffle_addr.to_string(),
+    });
+    let response = client.register(request).await?.into_inner();
+    if !version::compatible(response.protocol_version) {
+        return Err(format!(
+            "master {} speaks protocol version {} but worker {} supports versions {} to {}",
+            response.build_version,
+            response.protocol_version,
+            version::BUILD_VERSION,
+            version::MIN_PROTOCOL_VERSION,
+            version::PROTOCOL_VERSION
+        )
+        .into());
+    }
+    Ok(response.worker_id)
 }
 
 #[tokio::main]
 async fn main() {
-    // Initialize worker
-    let worker: Worker = Worker::new(process::id(), false);
-    worker
-        .boot()
+    let args: Vec<String> = env::args().collect();
+    let mut master_addr = MASTER_ADDR.to_string();
+    let mut shuffle_listen = SHUFFLE_LISTEN.to_string();
+    let mut data_dir = env::temp_dir().join(format!("mrlite-worker-{}", process::id()));
+    let (mut tls_ca, mut tls_domain) = (None, None);
+    for pair in args[1..].chunks(2) {
+        match pair {
+            [flag, value] if flag == "--master" => master_addr = value.clone(),
+            [flag, value] if flag == "--tls-ca" => tls_ca = Some(PathBuf::from(value)),
+            [flag, value] if flag == "--tls-domain" => tls_domain = Some(value.clone()),
+            [flag, value] if flag == "--shuffle-listen" => shuffle_listen = value.clone(),
+            [flag, value] if flag == "--data-dir" => data_dir = PathBuf::from(value),
+            _ => {
+                eprintln!("{USAGE}");
+                process::exit(2)
+            }
+        }
+    }
+    let tls = tls_ca.map(|ca_cert| ClientTls {
+        ca_cert,
+        domain: tls_domain,
+    });
+    let token = transport::token_from_env();
+    let bearer = BearerToken::new(token.as_deref()).unwrap_or_else(|err| {
+        eprintln!("ERROR: {err}");
+        process::exit(2)
+    });
+
+    // Serve map output to other workers from the start, since reduce tasks may fetch it as
+    // soon as a map task commits
+    let listener = TcpListener::bind(&shuffle_listen)
+        .await
+        .unwrap_or_else(|err| {
+            eprintln!("ERROR: Could not listen on {shuffle_listen}: {err}");
+            process::exit(1)
+        });
+    let shuffle_addr = advertised_addr(listener.local_addr().unwrap());
+    let shuffle_tokens = ShuffleTokens::default();
+    let shuffle_service = ShuffleService {
+        data_dir: data_dir.clone(),
+        shuffle_tokens: shuffle_tokens.clone(),
+    };
+    tokio::spawn(
+        Server::builder()
+            .add_service(ShuffleServer::new(shuffle_service))
+            .serve_with_incoming(TcpListenerStream::new(listener)),
+    );
+
+    // The channel reconnects on its own if the connection to the master drops
+    let channel = transport::connect(&master_addr, tls.as_ref())
+        .await
+        .unwrap_or_else(|err| {
+            eprintln!("ERROR: Could not connect to master at {master_addr}: {err}");
+            process::exit(1)
+        });
+    let mut client = TaskClient::with_interceptor(channel, bearer.clone());
+    let id = register(&mut client, &shuffle_addr)
         .await
-        .expect("ERROR: Could not boot worker process.");
+        .unwrap_or_else(|err| {
+            eprintln!("ERROR: Could not register with master: {err}");
+            process::exit(1)
+        });
+    println!(
+        "INFO: Registered as worker {}, serving map output on {}.",
+        id, shuffle_addr
+    );
+
+    // Initialize worker and keep taking tasks until the job is complete
+    let mut worker: Worker = Worker::new(id, false, client, data_dir, shuffle_addr, shuffle_tokens);
+    worker.start_heartbeats();
+    while !worker.done() {
+        worker
+            .boot()
+            .await
+            .expect("ERROR: Could not boot worker process.");
+    }
 }
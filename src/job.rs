@@ -0,0 +1,714 @@
+use crate::transport::{ListenAddr, ServerTls};
+use serde::{Deserialize, Serialize};
+use std::any::Any;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashSet;
+use std::fmt;
+use std::fs::{self, File};
+use std::hash::{Hash, Hasher};
+use std::io::prelude::*;
+use std::io::{self, BufReader, BufWriter, LineWriter};
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+#[derive(Serialize, Deserialize, Debug, Eq, Ord, PartialEq, PartialOrd)]
+pub struct KVPair {
+    pub key: String,
+    pub val: u64,
+}
+
+// Options controlling how the master schedules a job. Persisted with the job's state so that a
+// resumed job keeps the options it was started with.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct JobOptions {
+    // Attempts a task is given before the job is aborted, counting every assignment
+    pub max_attempts: i64,
+    // Failures of the user map or reduce function a task is allowed before the job is aborted.
+    // These are usually deterministic, so retrying them is rarely worth as much as retrying
+    // I/O errors or lost workers.
+    pub max_user_failures: i64,
+    // Whether workers should catch panics of the user functions per record, so that records
+    // which repeatedly crash them can be skipped
+    pub skip_bad_records: bool,
+    // Once the fraction of a phase's tasks that are not done drops to this, idle workers are
+    // given backup attempts of tasks that are still running. 0 disables backups.
+    pub backup_threshold: f64,
+}
+impl Default for JobOptions {
+    fn default() -> JobOptions {
+        JobOptions {
+            max_attempts: 4,
+            max_user_failures: 2,
+            skip_bad_records: false,
+            backup_threshold: 0.1,
+        }
+    }
+}
+impl JobOptions {
+    // In skip mode, records that have crashed a task this many times are skipped by its retries
+    pub const SKIP_AFTER_FAILURES: i64 = 2;
+
+    // Returns true if a task with this failure history may not be retried again
+    pub fn exhausted(&self, attempts: i64, user_failures: i64) -> bool {
+        attempts >= self.max_attempts || user_failures >= self.max_user_failures
+    }
+
+    // Returns true if a phase with `remaining` of its `total` tasks not done is close enough to
+    // finishing that stragglers should be backed up
+    pub fn wants_backups(&self, remaining: u64, total: u64) -> bool {
+        total > 0 && remaining as f64 / total as f64 <= self.backup_threshold
+    }
+}
+
+// Definition of a MapReduce job, shared by the master and the in-process executor
+#[derive(Clone, Debug, PartialEq)]
+pub struct Job {
+    pub n_map: i64,
+    pub n_reduce: i64,
+    pub inputs: Vec<String>,
+    pub options: JobOptions,
+}
+impl Job {
+    pub const USAGE: &'static str = "[--max-attempts <n>] [--max-user-failures <n>] \
+                                      [--skip-bad-records] [--backup-threshold <fraction>] \
+                                      <n_map> <n_reduce> <filename>...";
+
+    // Parses a job from `[--<option> <value>]... <n_map> <n_reduce> <filename>...`
+    pub fn from_args(mut args: &[String]) -> Result<Job, String> {
+        let mut options = JobOptions::default();
+        while let Some(flag) = args.first().filter(|arg| arg.starts_with("--")) {
+            if flag == "--skip-bad-records" {
+                options.skip_bad_records = true;
+                args = &args[1..];
+                continue;
+            }
+            let value = args
+                .get(1)
+                .ok_or_else(|| format!("{flag} expects a value"))?;
+            let parse_count = |value: &str| match i64::from_str(value) {
+                Ok(n) if n >= 1 => Ok(n),
+                _ => Err(format!("{flag} expects a positive number")),
+            };
+            match flag.as_str() {
+                "--max-attempts" => options.max_attempts = parse_count(value)?,
+                "--max-user-failures" => options.max_user_failures = parse_count(value)?,
+                "--backup-threshold" => {
+                    options.backup_threshold = match f64::from_str(value) {
+                        Ok(fraction) if (0.0..=1.0).contains(&fraction) => fraction,
+                        _ => return Err(format!("{flag} expects a fraction between 0 and 1")),
+                    }
+                }
+                _ => return Err(format!("unknown option {flag}")),
+            }
+            args = &args[2..];
+        }
+
+        if args.len() < 3 {
+            return Err("expected <n_map> <n_reduce> <filename>...".to_string());
+        }
+        let n_map = i64::from_str(&args[0]).map_err(|err| format!("invalid n_map: {err}"))?;
+        let n_reduce = i64::from_str(&args[1]).map_err(|err| format!("invalid n_reduce: {err}"))?;
+        let job = Job {
+            n_map,
+            n_reduce,
+            inputs: args[2..].to_vec(),
+            options,
+        };
+        job.check()?;
+        Ok(job)
+    }
+
+    // Checks that a job built elsewhere than from arguments, e.g. submitted over RPC, is one the
+    // master can run
+    pub fn check(&self) -> Result<(), String> {
+        if self.inputs.is_empty() {
+            return Err("a job needs at least one input file".to_string());
+        }
+        // Each input file is one map task, and reduce tasks read the output of every one
+        if self.n_map != self.inputs.len() as i64 {
+            return Err(format!(
+                "n_map must be the number of input files, {}",
+                self.inputs.len()
+            ));
+        }
+        // Map tasks are identified by their input file, so each can only be read once
+        let mut inputs = HashSet::new();
+        if let Some(input) = self.inputs.iter().find(|input| !inputs.insert(*input)) {
+            return Err(format!("input file {input} is given more than once"));
+        }
+        if self.n_reduce < 1 {
+            return Err("n_reduce must be at least 1".to_string());
+        }
+        if self.options.max_attempts < 1 || self.options.max_user_failures < 1 {
+            return Err("tasks must be allowed at least one attempt and failure".to_string());
+        }
+        if !(0.0..=1.0).contains(&self.options.backup_threshold) {
+            return Err("the backup threshold must be between 0 and 1".to_string());
+        }
+        Ok(())
+    }
+}
+
+// What the master does when asked to start a job whose id already has state in the store
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum IfExists {
+    Refuse,
+    Resume,
+    Overwrite,
+}
+
+// Arguments of the master: the job to run, and how to treat any state already stored for it
+#[derive(Clone, Debug, PartialEq)]
+pub struct MasterArgs {
+    // Id of the job, generated by the master if not given
+    pub job_id: Option<String>,
+    pub if_exists: IfExists,
+    // None when resuming a job whose definition is read back from the store
+    pub job: Option<Job>,
+    // Address the master serves workers on
+    pub listen: ListenAddr,
+    // Certificate and key to serve workers over TLS with, if any
+    pub tls: Option<ServerTls>,
+    // Whether to keep running without a job of its own, running the jobs submitted to it
+    pub serve: bool,
+}
+impl MasterArgs {
+    pub const DEFAULT_LISTEN: &'static str = "[::1]:50051";
+    pub const USAGE: &'static str = "[--listen <addr>] [--tls-cert <pem> --tls-key <pem>] \
+                                      [--job-id <id>] [--if-exists refuse|resume|overwrite] \
+                                      <job>\n       \
+                                      [--listen <addr>] [--tls-cert <pem> --tls-key <pem>] \
+                                      --resume <job-id>\n       \
+                                      [--listen <addr>] [--tls-cert <pem> --tls-key <pem>] \
+                                      --serve";
+
+    // Parses `[<server option>...] [--job-id <id>] [--if-exists <action>] <job>`,
+    // `[<server option>...] --resume <job-id>` or `[<server option>...] --serve`, where `<job>`
+    // is as accepted by `Job::from_args` and the server options are `--listen`, `--tls-cert` and
+    // `--tls-key`
+    pub fn from_args(mut args: &[String]) -> Result<MasterArgs, String> {
+        let mut master_args = MasterArgs {
+            job_id: None,
+            if_exists: IfExists::Refuse,
+            job: None,
+            listen: ListenAddr::from_str(MasterArgs::DEFAULT_LISTEN).unwrap(),
+            tls: None,
+            serve: false,
+        };
+        let mut resume = false;
+        let (mut tls_cert, mut tls_key) = (None, None);
+        let flags = [
+            "--job-id",
+            "--if-exists",
+            "--listen",
+            "--tls-cert",
+            "--tls-key",
+            "--resume",
+            "--serve",
+        ];
+        while let Some(flag) = args.first().filter(|arg| flags.contains(&arg.as_str())) {
+            if flag == "--serve" {
+                master_args.serve = true;
+                args = &args[1..];
+                continue;
+            }
+            let value = args
+                .get(1)
+                .ok_or_else(|| format!("{flag} expects a value"))?;
+            match flag.as_str() {
+                "--job-id" | "--resume" => {
+                    if !valid_job_id(value) {
+                        return Err(format!(
+                            "invalid job id {value}, expected letters, digits, - and _"
+                        ));
+                    }
+                    master_args.job_id = Some(value.clone());
+                    if flag == "--resume" {
+                        master_args.if_exists = IfExists::Resume;
+                        resume = true;
+                    }
+                }
+                "--if-exists" => {
+                    master_args.if_exists = match value.as_str() {
+                        "refuse" => IfExists::Refuse,
+                        "resume" => IfExists::Resume,
+                        "overwrite" => IfExists::Overwrite,
+                        _ => return Err(format!("{flag} expects refuse, resume or overwrite")),
+                    }
+                }
+                "--tls-cert" => tls_cert = Some(PathBuf::from(value)),
+                "--tls-key" => tls_key = Some(PathBuf::from(value)),
+                _ => {
+                    master_args.listen = ListenAddr::from_str(value).map_err(|err| {
+                        format!(
+                            "{flag} expects an address such as 0.0.0.0:50051 or unix:<path>: {err}"
+                        )
+                    })?
+                }
+            }
+            args = &args[2..];
+        }
+
+        master_args.tls = match (tls_cert, tls_key) {
+            (Some(cert), Some(key)) => Some(ServerTls { cert, key }),
+            (None, None) => None,
+            _ => return Err("--tls-cert and --tls-key must be given together".to_string()),
+        };
+
+        if master_args.serve {
+            if resume || master_args.job_id.is_some() || !args.is_empty() {
+                return Err("--serve takes no job, jobs are submitted to it".to_string());
+            }
+        } else if resume {
+            if !args.is_empty() {
+                return Err("--resume takes no job, it is read back from the store".to_string());
+            }
+        } else {
+            master_args.job = Some(Job::from_args(args)?);
+        }
+        Ok(master_args)
+    }
+
+    // URL that workers on this host can reach the master at
+    pub fn master_url(&self) -> String {
+        let url = self.listen.url();
+        match (&self.tls, url.strip_prefix("http://")) {
+            (Some(_), Some(addr)) => format!("https://{addr}"),
+            _ => url,
+        }
+    }
+}
+
+// Job ids name the job's database, so they are restricted to characters Mongo allows there
+pub fn valid_job_id(job_id: &str) -> bool {
+    !job_id.is_empty()
+        && job_id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+// User defined map function goes here
+pub fn map(contents: &str) -> Vec<KVPair> {
+    let mut kv_pairs = Vec::new();
+    for word in contents.split_whitespace() {
+        kv_pairs.push(KVPair {
+            key: word.to_string(),
+            val: 1,
+        });
+    }
+    kv_pairs
+}
+
+// User defined reduce function goes here
+pub fn reduce(_key: String, vals: Vec<u64>) -> u64 {
+    let mut total = 0;
+    for val in vals {
+        total += val;
+    }
+    total
+}
+
+// Name of the file holding the output of map task `map_tasknum` for reduce task `reduce_tasknum`
+pub fn intermediate_filename(dir: &Path, map_tasknum: i64, reduce_tasknum: i64) -> PathBuf {
+    dir.join(format!("map-{}-{}", map_tasknum, reduce_tasknum))
+}
+
+// Intermediate files written for reduce task `reduce_tasknum` by each of `n_map` map tasks
+pub fn intermediate_files(dir: &Path, n_map: i64, reduce_tasknum: i64) -> Vec<PathBuf> {
+    (0..n_map)
+        .map(|map_tasknum| intermediate_filename(dir, map_tasknum, reduce_tasknum))
+        .collect()
+}
+
+// Name of the file holding the final output of reduce task `reduce_tasknum`
+pub fn output_filename(dir: &Path, reduce_tasknum: i64) -> PathBuf {
+    dir.join(format!("out-{}", reduce_tasknum))
+}
+
+// Name of the file listing the records skipped by a map or reduce task in skip mode
+pub fn skipped_filename(dir: &Path, is_map: bool, tasknum: i64) -> PathBuf {
+    let phase = if is_map { "map" } else { "reduce" };
+    dir.join(format!("skipped-{}-{}", phase, tasknum))
+}
+
+// Reduce task responsible for a given key
+fn partition(key: &str, n_reduce: i64) -> i64 {
+    (calculate_hash(&key) % n_reduce as u64) as i64
+}
+
+fn calculate_hash<T: Hash>(t: &T) -> u64 {
+    let mut default_hasher = DefaultHasher::new();
+    t.hash(&mut default_hasher);
+    default_hasher.finish()
+}
+
+// Error from running a task in skip mode
+#[derive(Debug)]
+pub enum TaskError {
+    Io(io::Error),
+    // The user function panicked while processing the record at `offset`
+    BadRecord { offset: i64, message: String },
+}
+impl From<io::Error> for TaskError {
+    fn from(err: io::Error) -> TaskError {
+        TaskError::Io(err)
+    }
+}
+impl fmt::Display for TaskError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TaskError::Io(err) => write!(f, "{err}"),
+            TaskError::BadRecord { offset, message } => {
+                write!(f, "record at offset {offset} panicked: {message}")
+            }
+        }
+    }
+}
+
+// Extracts the message from the payload of a panic
+pub fn panic_message(payload: Box<dyn Any + Send>) -> String {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        message.to_string()
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message.clone()
+    } else {
+        "panic with a non-string payload".to_string()
+    }
+}
+
+// Runs a user function on the record at `offset`, turning a panic into a BadRecord error
+fn guard<T>(offset: i64, f: impl FnOnce() -> T) -> Result<T, TaskError> {
+    panic::catch_unwind(AssertUnwindSafe(f)).map_err(|payload| TaskError::BadRecord {
+        offset,
+        message: panic_message(payload),
+    })
+}
+
+// Logs skipped records, one `<offset>\t<record>` line each
+fn write_skipped(path: &Path, skipped: &[(i64, &str)]) -> io::Result<()> {
+    if skipped.is_empty() {
+        return Ok(());
+    }
+    let mut file = LineWriter::new(File::create(path)?);
+    for (offset, record) in skipped {
+        file.write_all(format!("{}\t{}\n", offset, record).as_bytes())?;
+    }
+    Ok(())
+}
+
+// Write map output to disk partitioned into one intermediate file per reduce task
+fn write_partitions(
+    dir: &Path,
+    map_tasknum: i64,
+    n_reduce: i64,
+    kv_pairs: Vec<KVPair>,
+) -> io::Result<()> {
+    let mut partitions: Vec<Vec<KVPair>> = (0..n_reduce).map(|_| Vec::new()).collect();
+    for kv in kv_pairs {
+        partitions[partition(&kv.key, n_reduce) as usize].push(kv);
+    }
+
+    for (reduce_tasknum, kv_pairs) in partitions.iter().enumerate() {
+        let filename = intermediate_filename(dir, map_tasknum, reduce_tasknum as i64);
+        let intermediate_file = BufWriter::new(File::create(filename)?);
+        serde_json::to_writer(intermediate_file, kv_pairs)?;
+    }
+
+    Ok(())
+}
+
+// Open file, call map function on its contents, and write results to disk partitioned into
+// one intermediate file per reduce task
+pub fn map_file(filepath: &str, dir: &Path, map_tasknum: i64, n_reduce: i64) -> io::Result<()> {
+    let file = File::open(filepath)?;
+    let mut buf_reader = BufReader::new(file);
+    let mut contents = String::new();
+
+    buf_reader.read_to_string(&mut contents)?;
+
+    write_partitions(dir, map_tasknum, n_reduce, map(&contents))
+}
+
+// Like map_file, but calls the map function once per line so that a line which makes it panic
+// can be identified by its byte offset. Lines at offsets in `skip` are not mapped, and are
+// logged to the task's skipped file instead.
+pub fn map_file_skipping(
+    filepath: &str,
+    dir: &Path,
+    map_tasknum: i64,
+    n_reduce: i64,
+    skip: &HashSet<i64>,
+) -> Result<(), TaskError> {
+    let contents = fs::read_to_string(filepath)?;
+
+    let mut kv_pairs = Vec::new();
+    let mut skipped = Vec::new();
+    let mut offset = 0;
+    for line in contents.split_inclusive('\n') {
+        let record = line.trim_end_matches('\n');
+        if skip.contains(&offset) {
+            skipped.push((offset, record));
+        } else {
+            kv_pairs.extend(guard(offset, || map(record))?);
+        }
+        offset += line.len() as i64;
+    }
+
+    write_skipped(&skipped_filename(dir, true, map_tasknum), &skipped)?;
+    Ok(write_partitions(dir, map_tasknum, n_reduce, kv_pairs)?)
+}
+
+// Gather the intermediate files of a reduce task from every map task, sorted by key. Every map
+// task writes a file for every partition, so a missing one means map output was lost.
+fn read_partition(inputs: &[PathBuf]) -> io::Result<Vec<KVPair>> {
+    let mut kv_pairs = Vec::new();
+    for path in inputs {
+        let file = File::open(path).map_err(|err| {
+            io::Error::new(
+                err.kind(),
+                format!("could not read map output {}: {err}", path.display()),
+            )
+        })?;
+        let partition: Vec<KVPair> = serde_json::from_reader(BufReader::new(file))?;
+        kv_pairs.extend(partition);
+    }
+    kv_pairs.sort();
+    Ok(kv_pairs)
+}
+
+// Gather the intermediate files of a reduce task from every map task, call the reduce function
+// on each key, and write results to `out_dir`
+pub fn reduce_partition(inputs: &[PathBuf], out_dir: &Path, reduce_tasknum: i64) -> io::Result<()> {
+    let kv_pairs = read_partition(inputs)?;
+    let file = File::create(output_filename(out_dir, reduce_tasknum))?;
+    let mut file = LineWriter::new(file);
+
+    // Now that the key value pairs are sorted, we can easily coalesce adjacent
+    // pairs if they are equivalent
+    for group in kv_pairs.chunk_by(|a, b| a.key == b.key) {
+        let vals = group.iter().map(|kv| kv.val).collect();
+        let count = reduce(group[0].key.clone(), vals);
+        file.write_all(format!("{} {}\n", group[0].key, count).as_bytes())?;
+    }
+
+    Ok(())
+}
+
+// Like reduce_partition, but catches panics of the reduce function. Keys are identified by
+// their position in sorted order, and keys at offsets in `skip` are logged to the task's
+// skipped file instead of being reduced.
+pub fn reduce_partition_skipping(
+    inputs: &[PathBuf],
+    out_dir: &Path,
+    reduce_tasknum: i64,
+    skip: &HashSet<i64>,
+) -> Result<(), TaskError> {
+    let kv_pairs = read_partition(inputs)?;
+    let file = File::create(output_filename(out_dir, reduce_tasknum))?;
+    let mut file = LineWriter::new(file);
+
+    let mut skipped = Vec::new();
+    for (offset, group) in kv_pairs.chunk_by(|a, b| a.key == b.key).enumerate() {
+        let offset = offset as i64;
+        let key = &group[0].key;
+        if skip.contains(&offset) {
+            skipped.push((offset, key.as_str()));
+            continue;
+        }
+        let vals = group.iter().map(|kv| kv.val).collect();
+        let count = guard(offset, || reduce(key.clone(), vals))?;
+        file.write_all(format!("{} {}\n", key, count).as_bytes())?;
+    }
+
+    write_skipped(&skipped_filename(out_dir, false, reduce_tasknum), &skipped)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    #[test]
+    fn test_from_args() {
+        let job = Job::from_args(&args(&["2", "3", "a.txt", "b.txt"])).unwrap();
+        assert_eq!(job.n_map, 2);
+        assert_eq!(job.n_reduce, 3);
+        assert_eq!(job.inputs, args(&["a.txt", "b.txt"]));
+        assert_eq!(job.options, JobOptions::default());
+
+        let job = Job::from_args(&args(&["--max-attempts", "7", "1", "1", "a.txt"])).unwrap();
+        assert_eq!(job.options.max_attempts, 7);
+
+        assert!(Job::from_args(&args(&["--max-attempts", "0", "1", "1", "a.txt"])).is_err());
+        let job = Job::from_args(&args(&["--skip-bad-records", "1", "1", "a.txt"])).unwrap();
+        assert!(job.options.skip_bad_records);
+
+        let job = Job::from_args(&args(&["--backup-threshold", "0.25", "1", "1", "a.txt"]));
+        assert_eq!(job.unwrap().options.backup_threshold, 0.25);
+        assert!(Job::from_args(&args(&["--backup-threshold", "2", "1", "1", "a.txt"])).is_err());
+
+        assert!(Job::from_args(&args(&["--bogus", "1", "1", "1", "a.txt"])).is_err());
+        assert!(Job::from_args(&args(&["1", "0", "a.txt"])).is_err());
+        assert!(Job::from_args(&args(&["1", "1", "a.txt", "b.txt"])).is_err());
+        assert!(Job::from_args(&args(&["3", "1", "a.txt", "b.txt"])).is_err());
+        assert!(Job::from_args(&args(&["0", "1", "a.txt"])).is_err());
+
+        let job = Job {
+            n_map: 1,
+            n_reduce: 1,
+            inputs: Vec::new(),
+            options: JobOptions::default(),
+        };
+        assert!(job.check().is_err());
+    }
+
+    #[test]
+    fn test_check() {
+        let job = |n_map, n_reduce, inputs: &[&str]| Job {
+            n_map,
+            n_reduce,
+            inputs: args(inputs),
+            options: JobOptions::default(),
+        };
+        assert!(job(2, 1, &["a.txt", "b.txt"]).check().is_ok());
+
+        // Jobs submitted over RPC are not parsed from arguments, so n_map is checked here too
+        assert!(job(1, 1, &["a.txt", "b.txt"]).check().is_err());
+        assert!(job(3, 1, &["a.txt", "b.txt"]).check().is_err());
+        assert!(job(-1, 1, &["a.txt"]).check().is_err());
+        assert!(job(1, 0, &["a.txt"]).check().is_err());
+        assert!(job(2, 1, &["a.txt", "a.txt"]).check().is_err());
+
+        let mut options = job(1, 1, &["a.txt"]);
+        options.options.max_user_failures = 0;
+        assert!(options.check().is_err());
+    }
+
+    #[test]
+    fn test_master_args() {
+        let master_args = MasterArgs::from_args(&args(&["1", "1", "a.txt"])).unwrap();
+        assert_eq!(master_args.job_id, None);
+        assert_eq!(master_args.if_exists, IfExists::Refuse);
+        assert!(master_args.job.is_some());
+
+        let master_args = MasterArgs::from_args(&args(&[
+            "--job-id",
+            "wc-1",
+            "--if-exists",
+            "overwrite",
+            "--max-attempts",
+            "2",
+            "1",
+            "1",
+            "a.txt",
+        ]))
+        .unwrap();
+        assert_eq!(master_args.job_id.as_deref(), Some("wc-1"));
+        assert_eq!(master_args.if_exists, IfExists::Overwrite);
+        assert_eq!(master_args.job.unwrap().options.max_attempts, 2);
+
+        let master_args = MasterArgs::from_args(&args(&["--resume", "wc-1"])).unwrap();
+        assert_eq!(master_args.if_exists, IfExists::Resume);
+        assert_eq!(master_args.job, None);
+
+        assert_eq!(master_args.master_url(), "http://[::1]:50051");
+
+        let master_args =
+            MasterArgs::from_args(&args(&["--listen", "0.0.0.0:6000", "--resume", "wc-1"]))
+                .unwrap();
+        assert_eq!(master_args.listen.to_string(), "0.0.0.0:6000");
+        assert_eq!(master_args.master_url(), "http://127.0.0.1:6000");
+        assert!(MasterArgs::from_args(&args(&["--listen", "localhost", "1", "1", "a"])).is_err());
+        let master_args =
+            MasterArgs::from_args(&args(&["--listen", "unix:/tmp/wc.sock", "1", "1", "a"]))
+                .unwrap();
+        assert_eq!(master_args.master_url(), "unix:/tmp/wc.sock");
+
+        let master_args = MasterArgs::from_args(&args(&[
+            "--tls-cert",
+            "server.pem",
+            "--tls-key",
+            "server.key",
+            "1",
+            "1",
+            "a",
+        ]))
+        .unwrap();
+        assert_eq!(master_args.master_url(), "https://[::1]:50051");
+        assert_eq!(master_args.tls.unwrap().key, PathBuf::from("server.key"));
+        assert!(
+            MasterArgs::from_args(&args(&["--tls-cert", "server.pem", "1", "1", "a"])).is_err()
+        );
+
+        assert!(MasterArgs::from_args(&args(&["--resume", "wc.1"])).is_err());
+        assert!(MasterArgs::from_args(&args(&["--resume", "wc-1", "1", "1", "a"])).is_err());
+        assert!(MasterArgs::from_args(&args(&["--if-exists", "keep", "1", "1", "a"])).is_err());
+
+        let master_args =
+            MasterArgs::from_args(&args(&["--listen", "0.0.0.0:6000", "--serve"])).unwrap();
+        assert!(master_args.serve);
+        assert_eq!(master_args.job, None);
+        assert!(MasterArgs::from_args(&args(&["--serve", "1", "1", "a"])).is_err());
+        assert!(MasterArgs::from_args(&args(&["--serve", "--resume", "wc-1"])).is_err());
+    }
+
+    #[test]
+    fn test_exhausted() {
+        let options = JobOptions::default();
+        assert!(!options.exhausted(1, 0));
+        assert!(!options.exhausted(3, 1));
+        assert!(options.exhausted(4, 0));
+        assert!(options.exhausted(2, 2));
+    }
+
+    #[test]
+    fn test_wants_backups() {
+        let options = JobOptions::default();
+        assert!(!options.wants_backups(5, 10));
+        assert!(options.wants_backups(1, 10));
+        assert!(!options.wants_backups(0, 0));
+
+        let options = JobOptions {
+            backup_threshold: 0.0,
+            ..JobOptions::default()
+        };
+        assert!(!options.wants_backups(1, 10));
+    }
+
+    #[test]
+    fn test_guard() {
+        assert_eq!(guard(3, || 7).unwrap(), 7);
+        match guard(5, || -> u64 { panic!("bad record") }) {
+            Err(TaskError::BadRecord { offset, message }) => {
+                assert_eq!(offset, 5);
+                assert_eq!(message, "bad record");
+            }
+            other => panic!("expected a bad record, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_map_file_skipping() {
+        let dir = std::env::temp_dir().join(format!("mrlite-skip-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let input = dir.join("input");
+        fs::write(&input, "a b\nbad line\nc\n").unwrap();
+
+        let skip = HashSet::from([4]);
+        map_file_skipping(input.to_str().unwrap(), &dir, 0, 1, &skip).unwrap();
+        reduce_partition(&intermediate_files(&dir, 1, 0), &dir, 0).unwrap();
+
+        let output = fs::read_to_string(output_filename(&dir, 0)).unwrap();
+        assert_eq!(output, "a 1\nb 1\nc 1\n");
+        assert!(reduce_partition(&intermediate_files(&dir, 2, 0), &dir, 0).is_err());
+        let skipped = fs::read_to_string(skipped_filename(&dir, true, 0)).unwrap();
+        assert_eq!(skipped, "4\tbad line\n");
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,8 @@
+pub mod job;
+pub mod local;
+pub mod location;
+pub mod registry;
+pub mod scheduler;
+pub mod shuffle;
+pub mod transport;
+pub mod version;
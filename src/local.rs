@@ -0,0 +1,144 @@
+// In-process executor that runs a job on a pool of threads, without the master, workers or
+// database. Produces the same `out-<r>` files as a distributed run.
+
+use crate::job::{self, Job};
+use std::collections::VecDeque;
+use std::io;
+use std::path::Path;
+use std::sync::Mutex;
+use std::thread;
+
+// Task state shared by the threads of a single phase
+struct PhaseState {
+    idle: VecDeque<i64>,
+    error: Option<io::Error>,
+}
+
+// Runs `run_task` for every task num in `tasknums` on `n_threads` threads, returning the first
+// error encountered. Threads stop taking new tasks once any task has failed.
+fn run_phase<F>(
+    tasknums: impl Iterator<Item = i64>,
+    n_threads: usize,
+    run_task: F,
+) -> io::Result<()>
+where
+    F: Fn(i64) -> io::Result<()> + Sync,
+{
+    let state = Mutex::new(PhaseState {
+        idle: tasknums.collect(),
+        error: None,
+    });
+
+    thread::scope(|scope| {
+        for _ in 0..n_threads.max(1) {
+            scope.spawn(|| loop {
+                let tasknum = {
+                    let mut state = state.lock().unwrap();
+                    if state.error.is_some() {
+                        return;
+                    }
+                    match state.idle.pop_front() {
+                        Some(tasknum) => tasknum,
+                        None => return,
+                    }
+                };
+                if let Err(err) = run_task(tasknum) {
+                    state.lock().unwrap().error.get_or_insert(err);
+                }
+            });
+        }
+    });
+
+    match state.into_inner().unwrap().error {
+        Some(err) => Err(err),
+        None => Ok(()),
+    }
+}
+
+// Runs every map task and then every reduce task of `job` on `n_threads` threads, writing
+// intermediate and output files to `dir`
+pub fn run(job: &Job, dir: &Path, n_threads: usize) -> io::Result<()> {
+    run_phase(0..job.inputs.len() as i64, n_threads, |tasknum| {
+        job::map_file(&job.inputs[tasknum as usize], dir, tasknum, job.n_reduce)
+    })?;
+    run_phase(0..job.n_reduce, n_threads, |tasknum| {
+        job::reduce_partition(
+            &job::intermediate_files(dir, job.n_map, tasknum),
+            dir,
+            tasknum,
+        )
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::job::JobOptions;
+    use std::collections::HashMap;
+    use std::env;
+    use std::fs;
+    use std::process;
+
+    fn read_outputs(dir: &Path, n_reduce: i64) -> Vec<String> {
+        (0..n_reduce)
+            .map(|r| fs::read_to_string(job::output_filename(dir, r)).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_word_count() {
+        let dir = env::temp_dir().join(format!("mrlite-local-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let inputs = [dir.join("in-0"), dir.join("in-1"), dir.join("in-2")];
+        fs::write(&inputs[0], "the quick brown fox\n").unwrap();
+        fs::write(&inputs[1], "jumps over\nthe lazy dog\n").unwrap();
+        fs::write(&inputs[2], "the end").unwrap();
+
+        let job = Job {
+            n_map: 3,
+            n_reduce: 4,
+            inputs: inputs
+                .iter()
+                .map(|path| path.to_str().unwrap().to_string())
+                .collect(),
+            options: JobOptions::default(),
+        };
+
+        let serial_dir = dir.join("serial");
+        let parallel_dir = dir.join("parallel");
+        fs::create_dir_all(&serial_dir).unwrap();
+        fs::create_dir_all(&parallel_dir).unwrap();
+        run(&job, &serial_dir, 1).unwrap();
+        run(&job, &parallel_dir, 8).unwrap();
+
+        let outputs = read_outputs(&serial_dir, job.n_reduce);
+        assert_eq!(outputs, read_outputs(&parallel_dir, job.n_reduce));
+
+        let mut counts = HashMap::new();
+        for line in outputs.iter().flat_map(|out| out.lines()) {
+            let (word, count) = line.split_once(' ').unwrap();
+            assert!(counts.insert(word.to_string(), count.to_string()).is_none());
+        }
+        assert_eq!(counts.len(), 9);
+        assert_eq!(counts["the"], "3");
+        assert_eq!(counts["fox"], "1");
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_missing_input() {
+        let dir = env::temp_dir().join(format!("mrlite-local-missing-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let job = Job {
+            n_map: 1,
+            n_reduce: 1,
+            inputs: vec![dir.join("does-not-exist").to_str().unwrap().to_string()],
+            options: JobOptions::default(),
+        };
+
+        assert!(run(&job, &dir, 2).is_err());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
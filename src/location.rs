@@ -0,0 +1,86 @@
+// Locations of intermediate files, which the master hands to reduce tasks as URLs so that they
+// can name data held anywhere, not just in the reducer's working directory. Map output is
+// normally served by the worker that produced it, at a `shuffle://` URL; `file://` URLs name
+// files on a filesystem every worker can read.
+
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Location {
+    File(PathBuf),
+    // Partition `partition` of the output of map task `map_tasknum`, served by the worker
+    // listening on `addr`
+    Shuffle {
+        addr: String,
+        map_tasknum: i64,
+        partition: i64,
+    },
+}
+impl Location {
+    pub fn file(path: &Path) -> Location {
+        Location::File(path.to_path_buf())
+    }
+}
+impl fmt::Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Location::File(path) => write!(f, "file://{}", path.display()),
+            Location::Shuffle {
+                addr,
+                map_tasknum,
+                partition,
+            } => write!(f, "shuffle://{addr}/map-{map_tasknum}-{partition}"),
+        }
+    }
+}
+impl FromStr for Location {
+    type Err = String;
+
+    fn from_str(url: &str) -> Result<Location, String> {
+        match url.split_once("://") {
+            Some(("file", path)) if !path.is_empty() => Ok(Location::File(PathBuf::from(path))),
+            Some(("shuffle", rest)) => {
+                let parse = || {
+                    let (addr, name) = rest.rsplit_once('/')?;
+                    let (map_tasknum, partition) = name.strip_prefix("map-")?.split_once('-')?;
+                    Some(Location::Shuffle {
+                        addr: addr.to_string(),
+                        map_tasknum: map_tasknum.parse().ok()?,
+                        partition: partition.parse().ok()?,
+                    })
+                };
+                parse()
+                    .filter(|_| !rest.starts_with('/'))
+                    .ok_or_else(|| format!("invalid shuffle location {url}"))
+            }
+            Some((scheme, _)) => Err(format!("unsupported location scheme {scheme} in {url}")),
+            None => Err(format!("invalid location {url}, expected a URL")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        let location = Location::file(Path::new("/data/job/map-0-1"));
+        assert_eq!(location.to_string(), "file:///data/job/map-0-1");
+        assert_eq!(location.to_string().parse(), Ok(location));
+
+        let location = Location::Shuffle {
+            addr: "[::1]:40000".to_string(),
+            map_tasknum: 3,
+            partition: 1,
+        };
+        assert_eq!(location.to_string(), "shuffle://[::1]:40000/map-3-1");
+        assert_eq!(location.to_string().parse(), Ok(location));
+
+        assert!("shuffle://[::1]:40000/out-1".parse::<Location>().is_err());
+        assert!("/data/job/map-0-1".parse::<Location>().is_err());
+        assert!("ftp://host/map-0-1".parse::<Location>().is_err());
+    }
+}
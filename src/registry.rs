@@ -0,0 +1,238 @@
+// Registry of the workers known to the master, kept up to date by heartbeats
+
+use std::collections::HashMap;
+use std::time::{Duration, Instant, SystemTime};
+
+// A map or reduce task, identified by its name and phase
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct TaskRef {
+    pub name: String,
+    pub is_map: bool,
+}
+
+// A task of one of the jobs the master is running. Masters run several jobs at once, whose
+// tasks can share names.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct JobTask {
+    pub job_id: String,
+    pub task: TaskRef,
+}
+
+#[derive(Clone, Debug)]
+pub struct WorkerInfo {
+    pub pid: u32,
+    pub hostname: String,
+    // Address the worker serves its map output on, as host:port
+    pub shuffle_addr: String,
+    pub started_at: SystemTime,
+    pub last_seen: Instant,
+    pub current_task: Option<JobTask>,
+    pub tasks_completed: u64,
+    // Set when the worker has been asked to leave once its current task is done
+    pub draining: bool,
+    // Jobs that have finished since the worker last asked for a task, whose map output it can
+    // remove
+    pub finished_jobs: Vec<String>,
+}
+
+// Tracks live workers by the id the master issued to them when they registered. Ids are
+// never reused, so a worker that has been expired cannot be confused with a new one.
+#[derive(Debug, Default)]
+pub struct WorkerRegistry {
+    workers: HashMap<u64, WorkerInfo>,
+    next_id: u64,
+}
+impl WorkerRegistry {
+    pub fn new() -> WorkerRegistry {
+        WorkerRegistry::default()
+    }
+
+    // Adds a worker to the registry, returning its newly issued id
+    pub fn register(&mut self, pid: u32, hostname: &str, shuffle_addr: &str, now: Instant) -> u64 {
+        self.next_id += 1;
+        self.workers.insert(
+            self.next_id,
+            WorkerInfo {
+                pid,
+                hostname: hostname.to_string(),
+                shuffle_addr: shuffle_addr.to_string(),
+                started_at: SystemTime::now(),
+                last_seen: now,
+                current_task: None,
+                tasks_completed: 0,
+                draining: false,
+                finished_jobs: Vec::new(),
+            },
+        );
+        self.next_id
+    }
+
+    // Records a heartbeat from a worker. Returns false if the worker is not registered. The
+    // task a worker runs is the one it was assigned until it reports how the attempt ended: a
+    // heartbeat sent before the worker picked up its assignment must not make the master forget
+    // it, or the task would never be retried if the worker died.
+    pub fn heartbeat(&mut self, id: u64, tasks_completed: u64, now: Instant) -> bool {
+        let Some(worker) = self.workers.get_mut(&id) else {
+            return false;
+        };
+        worker.last_seen = now;
+        worker.tasks_completed = tasks_completed;
+        true
+    }
+
+    // Records that a task has been handed out to a worker. Returns false if the worker is not
+    // registered.
+    pub fn assign(&mut self, id: u64, task: JobTask, now: Instant) -> bool {
+        let Some(worker) = self.workers.get_mut(&id) else {
+            return false;
+        };
+        worker.last_seen = now;
+        worker.current_task = Some(task);
+        true
+    }
+
+    // Records that a worker is no longer running its task
+    pub fn finish(&mut self, id: u64, now: Instant) {
+        if let Some(worker) = self.workers.get_mut(&id) {
+            worker.last_seen = now;
+            worker.current_task = None;
+        }
+    }
+
+    // Returns the ids of the workers currently running a task
+    pub fn running(&self, task: &JobTask) -> Vec<u64> {
+        self.workers
+            .iter()
+            .filter(|(_, worker)| worker.current_task.as_ref() == Some(task))
+            .map(|(id, _)| *id)
+            .collect()
+    }
+
+    // Marks a worker to be told to leave the next time it asks for a task. Returns false if the
+    // worker is not registered.
+    pub fn drain(&mut self, id: u64) -> bool {
+        let Some(worker) = self.workers.get_mut(&id) else {
+            return false;
+        };
+        worker.draining = true;
+        true
+    }
+
+    // Records that a job has finished, to tell every worker once
+    pub fn job_finished(&mut self, job_id: &str) {
+        for worker in self.workers.values_mut() {
+            worker.finished_jobs.push(job_id.to_string());
+        }
+    }
+
+    // Returns the jobs that have finished since a worker was last told
+    pub fn take_finished_jobs(&mut self, id: u64) -> Vec<String> {
+        self.workers
+            .get_mut(&id)
+            .map(|worker| std::mem::take(&mut worker.finished_jobs))
+            .unwrap_or_default()
+    }
+
+    pub fn remove(&mut self, id: u64) -> Option<WorkerInfo> {
+        self.workers.remove(&id)
+    }
+
+    pub fn contains(&self, id: u64) -> bool {
+        self.workers.contains_key(&id)
+    }
+
+    pub fn get(&self, id: u64) -> Option<&WorkerInfo> {
+        self.workers.get(&id)
+    }
+
+    pub fn workers(&self) -> impl Iterator<Item = (&u64, &WorkerInfo)> {
+        self.workers.iter()
+    }
+
+    // Removes and returns every worker that has not been seen within `timeout` of `now`
+    pub fn expire(&mut self, now: Instant, timeout: Duration) -> Vec<(u64, WorkerInfo)> {
+        let dead: Vec<u64> = self
+            .workers
+            .iter()
+            .filter(|(_, worker)| now.duration_since(worker.last_seen) > timeout)
+            .map(|(id, _)| *id)
+            .collect();
+        dead.into_iter()
+            .map(|id| (id, self.workers.remove(&id).unwrap()))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_expire() {
+        let start = Instant::now();
+        let timeout = Duration::from_secs(10);
+        let task = JobTask {
+            job_id: "job-1".to_string(),
+            task: TaskRef {
+                name: "input.txt".to_string(),
+                is_map: true,
+            },
+        };
+        let mut registry = WorkerRegistry::new();
+
+        let first = registry.register(100, "host-a", "host-a:4000", start);
+        let second = registry.register(100, "host-b", "host-b:4000", start);
+        assert_ne!(first, second);
+
+        assert!(registry.assign(first, task.clone(), start));
+        assert_eq!(registry.running(&task), vec![first]);
+        // A heartbeat sent before the worker picked up the task keeps it assigned
+        assert!(registry.heartbeat(first, 0, start));
+        assert_eq!(registry.running(&task), vec![first]);
+        let other_job = JobTask {
+            job_id: "job-2".to_string(),
+            ..task.clone()
+        };
+        assert!(registry.running(&other_job).is_empty());
+        assert!(registry.heartbeat(second, 3, start));
+        assert!(registry.heartbeat(second, 4, start + Duration::from_secs(8)));
+
+        let dead = registry.expire(start + Duration::from_secs(12), timeout);
+        assert_eq!(dead.len(), 1);
+        assert_eq!(dead[0].0, first);
+        assert_eq!(dead[0].1.current_task, Some(task));
+
+        let alive = registry.get(second).unwrap();
+        assert_eq!(alive.hostname, "host-b");
+        assert_eq!(alive.shuffle_addr, "host-b:4000");
+        assert_eq!(alive.tasks_completed, 4);
+        assert!(!registry.contains(first));
+        assert!(!registry.heartbeat(first, 0, start));
+
+        // Ids of expired workers are not handed out again
+        let third = registry.register(101, "host-a", "host-a:4001", start);
+        assert!(third > second);
+
+        assert!(registry.drain(third));
+        assert!(!registry.drain(first));
+        assert!(registry.remove(third).unwrap().draining);
+        assert!(!registry.get(second).unwrap().draining);
+    }
+
+    #[test]
+    fn test_finished_jobs() {
+        let start = Instant::now();
+        let mut registry = WorkerRegistry::new();
+        let first = registry.register(100, "host-a", "host-a:4000", start);
+        registry.job_finished("job-1");
+        let second = registry.register(101, "host-b", "host-b:4000", start);
+        registry.job_finished("job-2");
+
+        // Each worker is told about each job once, and only about jobs that finished after it
+        // registered
+        assert_eq!(registry.take_finished_jobs(first), ["job-1", "job-2"]);
+        assert!(registry.take_finished_jobs(first).is_empty());
+        assert_eq!(registry.take_finished_jobs(second), ["job-2"]);
+        assert!(registry.take_finished_jobs(second + 1).is_empty());
+    }
+}
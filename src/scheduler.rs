@@ -0,0 +1,278 @@
+// In-memory view of the state of a job's tasks, which lets the master hand out tasks in
+// constant time instead of scanning the store. The store stays the source of truth across
+// restarts: the master writes every change made here through to it, and reloads the scheduler
+// from it when it starts.
+
+use crate::registry::TaskRef;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fmt;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TaskState {
+    Idle,
+    InProgress,
+    Completed,
+}
+
+// Number of tasks of a phase in each state
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct PhaseProgress {
+    pub idle: i64,
+    pub in_progress: i64,
+    pub completed: i64,
+}
+impl PhaseProgress {
+    pub fn total(&self) -> i64 {
+        self.idle + self.in_progress + self.completed
+    }
+
+    pub fn remaining(&self) -> i64 {
+        self.idle + self.in_progress
+    }
+
+    pub fn done(&self) -> bool {
+        self.remaining() == 0
+    }
+}
+impl fmt::Display for PhaseProgress {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}/{} done, {} in progress",
+            self.completed,
+            self.total(),
+            self.in_progress
+        )
+    }
+}
+
+// Tasks of one phase, indexed by state
+#[derive(Debug, Default)]
+struct Phase {
+    // Idle tasks, in the order they are handed out
+    idle: VecDeque<String>,
+    in_progress: HashSet<String>,
+    completed: i64,
+}
+impl Phase {
+    fn progress(&self) -> PhaseProgress {
+        PhaseProgress {
+            idle: self.idle.len() as i64,
+            in_progress: self.in_progress.len() as i64,
+            completed: self.completed,
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Scheduler {
+    // State and task num of every task
+    tasks: HashMap<TaskRef, (TaskState, i32)>,
+    // Every task by phase and task num, for looking up the map tasks whose output was lost
+    tasknums: HashMap<(bool, i32), TaskRef>,
+    map: Phase,
+    reduce: Phase,
+}
+impl Scheduler {
+    pub fn new() -> Scheduler {
+        Scheduler::default()
+    }
+
+    fn phase(&mut self, is_map: bool) -> &mut Phase {
+        if is_map {
+            &mut self.map
+        } else {
+            &mut self.reduce
+        }
+    }
+
+    // Adds a task in the given state, as loaded from the store
+    pub fn add(&mut self, task: TaskRef, tasknum: i32, state: TaskState) {
+        let phase = self.phase(task.is_map);
+        match state {
+            TaskState::Idle => phase.idle.push_back(task.name.clone()),
+            TaskState::InProgress => {
+                phase.in_progress.insert(task.name.clone());
+            }
+            TaskState::Completed => phase.completed += 1,
+        }
+        self.tasknums.insert((task.is_map, tasknum), task.clone());
+        self.tasks.insert(task, (state, tasknum));
+    }
+
+    // The phase tasks are currently handed out from: true for map, false for reduce, or None
+    // once every task is completed. Reduce tasks wait for every map task to complete.
+    pub fn current_phase(&self) -> Option<bool> {
+        if !self.map.progress().done() {
+            Some(true)
+        } else if !self.reduce.progress().done() {
+            Some(false)
+        } else {
+            None
+        }
+    }
+
+    pub fn done(&self) -> bool {
+        self.current_phase().is_none()
+    }
+
+    pub fn progress(&self, is_map: bool) -> PhaseProgress {
+        if is_map {
+            self.map.progress()
+        } else {
+            self.reduce.progress()
+        }
+    }
+
+    pub fn state(&self, task: &TaskRef) -> Option<TaskState> {
+        self.tasks.get(task).map(|(state, _)| *state)
+    }
+
+    pub fn tasknum(&self, task: &TaskRef) -> Option<i32> {
+        self.tasks.get(task).map(|(_, tasknum)| *tasknum)
+    }
+
+    // The task of a phase with the given task num
+    pub fn find(&self, is_map: bool, tasknum: i32) -> Option<TaskRef> {
+        self.tasknums.get(&(is_map, tasknum)).cloned()
+    }
+
+    // Takes the next idle task of the current phase and marks it in progress, returning it and
+    // its task num
+    pub fn next_idle(&mut self) -> Option<(TaskRef, i32)> {
+        let is_map = self.current_phase()?;
+        let phase = self.phase(is_map);
+        let name = phase.idle.pop_front()?;
+        phase.in_progress.insert(name.clone());
+
+        let task = TaskRef { name, is_map };
+        let entry = self.tasks.get_mut(&task).unwrap();
+        entry.0 = TaskState::InProgress;
+        let tasknum = entry.1;
+        Some((task, tasknum))
+    }
+
+    // The in-progress tasks of a phase and their task nums
+    pub fn in_progress(&self, is_map: bool) -> Vec<(String, i32)> {
+        let phase = if is_map { &self.map } else { &self.reduce };
+        phase
+            .in_progress
+            .iter()
+            .map(|name| {
+                let task = TaskRef {
+                    name: name.clone(),
+                    is_map,
+                };
+                (name.clone(), self.tasks[&task].1)
+            })
+            .collect()
+    }
+
+    // Returns an in-progress task to the back of the idle queue. Returns false if the task was
+    // not in progress.
+    pub fn requeue(&mut self, task: &TaskRef) -> bool {
+        match self.tasks.get_mut(task) {
+            Some(entry) if entry.0 == TaskState::InProgress => entry.0 = TaskState::Idle,
+            _ => return false,
+        }
+        let phase = self.phase(task.is_map);
+        phase.in_progress.remove(&task.name);
+        phase.idle.push_back(task.name.clone());
+        true
+    }
+
+    // Returns a completed task to the front of the idle queue, e.g. because its output was
+    // lost. Returns false if the task was not completed.
+    pub fn reset(&mut self, task: &TaskRef) -> bool {
+        match self.tasks.get_mut(task) {
+            Some(entry) if entry.0 == TaskState::Completed => entry.0 = TaskState::Idle,
+            _ => return false,
+        }
+        let phase = self.phase(task.is_map);
+        phase.completed -= 1;
+        phase.idle.push_front(task.name.clone());
+        true
+    }
+
+    // Marks a task completed, returning the state it was in, or None if it was already
+    // completed or is unknown
+    pub fn complete(&mut self, task: &TaskRef) -> Option<TaskState> {
+        let entry = self.tasks.get_mut(task)?;
+        let previous = entry.0;
+        match previous {
+            TaskState::Completed => return None,
+            _ => entry.0 = TaskState::Completed,
+        }
+        let phase = self.phase(task.is_map);
+        match previous {
+            TaskState::InProgress => {
+                phase.in_progress.remove(&task.name);
+            }
+            // Only happens if an attempt commits after its task was requeued, e.g. because its
+            // worker was wrongly thought to be dead
+            _ => phase.idle.retain(|name| *name != task.name),
+        }
+        phase.completed += 1;
+        Some(previous)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn task(name: &str, is_map: bool) -> TaskRef {
+        TaskRef {
+            name: name.to_string(),
+            is_map,
+        }
+    }
+
+    #[test]
+    fn test_phases() {
+        let mut scheduler = Scheduler::new();
+        scheduler.add(task("a.txt", true), 0, TaskState::Idle);
+        scheduler.add(task("b.txt", true), 1, TaskState::Completed);
+        scheduler.add(task("0", false), -1, TaskState::Idle);
+        assert_eq!(scheduler.current_phase(), Some(true));
+
+        // Reduce tasks are not handed out while a map task is running
+        let (a, tasknum) = scheduler.next_idle().unwrap();
+        assert_eq!((a.name.as_str(), tasknum), ("a.txt", 0));
+        assert_eq!(scheduler.next_idle(), None);
+        assert_eq!(scheduler.in_progress(true), vec![("a.txt".to_string(), 0)]);
+
+        assert!(scheduler.requeue(&a));
+        assert!(!scheduler.requeue(&a));
+        assert_eq!(scheduler.next_idle().unwrap().0, a);
+        assert_eq!(scheduler.complete(&a), Some(TaskState::InProgress));
+        assert_eq!(scheduler.complete(&a), None);
+        assert_eq!(
+            scheduler.progress(true),
+            PhaseProgress {
+                idle: 0,
+                in_progress: 0,
+                completed: 2
+            }
+        );
+
+        assert_eq!(scheduler.current_phase(), Some(false));
+        let (r, _) = scheduler.next_idle().unwrap();
+
+        // Losing a completed map task's output sends the job back to the map phase
+        let b = scheduler.find(true, 1).unwrap();
+        assert_eq!(scheduler.find(false, 1), None);
+        assert_eq!(scheduler.find(true, 2), None);
+        assert!(scheduler.reset(&b));
+        assert!(!scheduler.reset(&b));
+        assert_eq!(scheduler.current_phase(), Some(true));
+        assert_eq!(scheduler.next_idle().unwrap().0, b);
+        assert_eq!(scheduler.complete(&b), Some(TaskState::InProgress));
+
+        assert!(scheduler.requeue(&r));
+        // A requeued task can still be committed by the attempt that was running it
+        assert_eq!(scheduler.complete(&r), Some(TaskState::Idle));
+        assert_eq!(scheduler.next_idle(), None);
+        assert!(scheduler.done());
+    }
+}
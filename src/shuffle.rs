@@ -0,0 +1,153 @@
+// Where the output of each completed map task lives. Map output stays on the disk of the worker
+// that produced it, which serves it to reduce tasks over the `Shuffle` RPC service, so the
+// master has to tell reducers which worker to fetch each partition from. Output that is lost
+// with its worker has to be produced again by running the map task again.
+
+use crate::location::Location;
+use std::collections::HashMap;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MapOutput {
+    // Id of the worker that committed the map task
+    pub worker_id: u64,
+    // Address the worker serves its map output on, as host:port
+    pub addr: String,
+}
+
+// Output of the completed map tasks, by map task num
+#[derive(Debug, Default)]
+pub struct MapOutputs {
+    outputs: HashMap<i64, MapOutput>,
+}
+impl MapOutputs {
+    pub fn new() -> MapOutputs {
+        MapOutputs::default()
+    }
+
+    pub fn insert(&mut self, map_tasknum: i64, output: MapOutput) {
+        self.outputs.insert(map_tasknum, output);
+    }
+
+    pub fn get(&self, map_tasknum: i64) -> Option<&MapOutput> {
+        self.outputs.get(&map_tasknum)
+    }
+
+    // Forgets the output held by a worker that was lost, returning the task nums of the map
+    // tasks whose output went with it
+    pub fn remove_worker(&mut self, worker_id: u64) -> Vec<i64> {
+        let mut lost: Vec<i64> = self
+            .outputs
+            .iter()
+            .filter(|(_, output)| output.worker_id == worker_id)
+            .map(|(map_tasknum, _)| *map_tasknum)
+            .collect();
+        lost.sort();
+        for map_tasknum in &lost {
+            self.outputs.remove(map_tasknum);
+        }
+        lost
+    }
+
+    // Forgets the output of every worker `held` does not vouch for, e.g. the output a previous
+    // master recorded, whose workers this master does not know. Returns the task nums of the map
+    // tasks whose output was forgotten.
+    pub fn retain_held(&mut self, held: impl Fn(&MapOutput) -> bool) -> Vec<i64> {
+        let mut lost: Vec<i64> = self
+            .outputs
+            .iter()
+            .filter(|(_, output)| !held(output))
+            .map(|(map_tasknum, _)| *map_tasknum)
+            .collect();
+        lost.sort();
+        for map_tasknum in &lost {
+            self.outputs.remove(map_tasknum);
+        }
+        lost
+    }
+
+    // Forgets a map task's output after a reducer failed to fetch it from `addr`. Returns false
+    // if the output is no longer expected to be there, e.g. because the map task was already
+    // run again elsewhere.
+    pub fn remove_at(&mut self, map_tasknum: i64, addr: &str) -> bool {
+        match self.outputs.get(&map_tasknum) {
+            Some(output) if output.addr == addr => {
+                self.outputs.remove(&map_tasknum);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    // Location of one partition of a map task's output, or None if the task's output is not
+    // known to be held by any worker
+    pub fn location(&self, map_tasknum: i64, partition: i64) -> Option<Location> {
+        self.outputs
+            .get(&map_tasknum)
+            .map(|output| Location::Shuffle {
+                addr: output.addr.clone(),
+                map_tasknum,
+                partition,
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_location() {
+        let mut outputs = MapOutputs::new();
+        outputs.insert(
+            0,
+            MapOutput {
+                worker_id: 7,
+                addr: "host-a:4000".to_string(),
+            },
+        );
+        assert_eq!(
+            outputs.location(0, 2).unwrap().to_string(),
+            "shuffle://host-a:4000/map-0-2"
+        );
+        assert_eq!(outputs.get(0).unwrap().worker_id, 7);
+        assert_eq!(outputs.location(1, 2), None);
+
+        outputs.insert(
+            1,
+            MapOutput {
+                worker_id: 8,
+                addr: "host-b:4000".to_string(),
+            },
+        );
+        assert_eq!(outputs.remove_worker(7), vec![0]);
+        assert_eq!(outputs.location(0, 2), None);
+        // Fetch failures only count against the output the reducer was told about
+        assert!(!outputs.remove_at(1, "host-a:4000"));
+        assert!(outputs.remove_at(1, "host-b:4000"));
+        assert_eq!(outputs.get(1), None);
+    }
+
+    #[test]
+    fn test_retain_held() {
+        let mut outputs = MapOutputs::new();
+        for (map_tasknum, worker_id, addr) in [(0, 1, "host-a:4000"), (1, 1, "host-b:4000")] {
+            let addr = addr.to_string();
+            outputs.insert(map_tasknum, MapOutput { worker_id, addr });
+        }
+        outputs.insert(
+            2,
+            MapOutput {
+                worker_id: 2,
+                addr: "host-c:4000".to_string(),
+            },
+        );
+
+        // A worker that reuses the id of another one does not hold that worker's output
+        let registered = [(1, "host-a:4000")];
+        let held = |output: &MapOutput| registered.contains(&(output.worker_id, &output.addr));
+        assert_eq!(outputs.retain_held(held), vec![1, 2]);
+        assert!(outputs.location(0, 0).is_some());
+        assert_eq!(outputs.location(1, 0), None);
+        assert!(outputs.retain_held(|_| true).is_empty());
+    }
+}
@@ -0,0 +1,308 @@
+// Addresses the master serves workers on: a TCP address, or, for jobs running on a single
+// machine, a Unix domain socket. Sockets avoid port conflicts between jobs running side by side,
+// and their file permissions keep other users from joining a job. Over TCP, connections can be
+// secured with TLS, and servers can require clients to present a shared token.
+
+use std::fmt;
+use std::io;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::str::FromStr;
+use std::task::{Context, Poll};
+use std::{env, fs, os::unix::net};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::UnixListener;
+use tokio_stream::wrappers::UnixListenerStream;
+use tokio_stream::{Stream, StreamExt};
+use tonic::metadata::{Ascii, MetadataValue};
+use tonic::service::Interceptor;
+use tonic::transport::server::Connected;
+use tonic::transport::{
+    Certificate, Channel, ClientTlsConfig, Endpoint, Identity, ServerTlsConfig, Uri,
+};
+use tonic::{Request, Status};
+use tower::service_fn;
+
+// Environment variable holding the token that workers must present to join a job. Read from the
+// environment rather than the command line so that it does not show up in process listings.
+pub const TOKEN_VAR: &str = "MRLITE_TOKEN";
+
+// The shared token from the environment, if one is set
+pub fn token_from_env() -> Option<String> {
+    env::var(TOKEN_VAR).ok().filter(|token| !token.is_empty())
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ListenAddr {
+    Tcp(SocketAddr),
+    Unix(PathBuf),
+}
+impl ListenAddr {
+    // URL that clients on this host can reach a server listening here at, as accepted by
+    // `connect`
+    pub fn url(&self) -> String {
+        match self {
+            ListenAddr::Tcp(addr) => {
+                let mut addr = *addr;
+                if addr.ip().is_unspecified() {
+                    addr.set_ip(match addr {
+                        SocketAddr::V4(_) => Ipv4Addr::LOCALHOST.into(),
+                        SocketAddr::V6(_) => Ipv6Addr::LOCALHOST.into(),
+                    });
+                }
+                format!("http://{addr}")
+            }
+            ListenAddr::Unix(path) => format!("unix:{}", path.display()),
+        }
+    }
+}
+impl fmt::Display for ListenAddr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ListenAddr::Tcp(addr) => write!(f, "{addr}"),
+            ListenAddr::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+impl FromStr for ListenAddr {
+    type Err = String;
+
+    // Parses `host:port` or `unix:<path>`
+    fn from_str(addr: &str) -> Result<ListenAddr, String> {
+        match addr.strip_prefix("unix:") {
+            Some("") => Err("unix: expects the path of a socket".to_string()),
+            Some(path) => Ok(ListenAddr::Unix(PathBuf::from(path))),
+            None => SocketAddr::from_str(addr)
+                .map(ListenAddr::Tcp)
+                .map_err(|err| format!("invalid address {addr}: {err}")),
+        }
+    }
+}
+
+// Certificate and private key a server proves its identity with, as PEM files
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ServerTls {
+    pub cert: PathBuf,
+    pub key: PathBuf,
+}
+impl ServerTls {
+    pub fn config(&self) -> io::Result<ServerTlsConfig> {
+        let identity = Identity::from_pem(fs::read(&self.cert)?, fs::read(&self.key)?);
+        Ok(ServerTlsConfig::new().identity(identity))
+    }
+}
+
+// How a client checks the identity of a TLS server: the PEM file of the certificate authority
+// that issued the server's certificate, and the name the certificate was issued to if it is not
+// the host the client connects to
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ClientTls {
+    pub ca_cert: PathBuf,
+    pub domain: Option<String>,
+}
+impl ClientTls {
+    pub fn config(&self) -> io::Result<ClientTlsConfig> {
+        let config =
+            ClientTlsConfig::new().ca_certificate(Certificate::from_pem(fs::read(&self.ca_cert)?));
+        Ok(match &self.domain {
+            Some(domain) => config.domain_name(domain),
+            None => config,
+        })
+    }
+}
+
+// Opens a channel to a server at `url`: either an `http://` or `https://` URL, or
+// `unix:<path>`. Connections are made over TLS if `tls` is given.
+pub async fn connect(
+    url: &str,
+    tls: Option<&ClientTls>,
+) -> Result<Channel, Box<dyn std::error::Error>> {
+    let path = url.strip_prefix("unix:").map(PathBuf::from);
+    // Channels to a socket need a URI, but every connection is made to the socket
+    let mut endpoint = match &path {
+        Some(_) => Endpoint::from_static("http://localhost"),
+        None => Endpoint::from_shared(url.to_string())?,
+    };
+    if let Some(tls) = tls {
+        endpoint = endpoint.tls_config(tls.config()?)?;
+    }
+    let channel = match path {
+        Some(path) => {
+            endpoint
+                .connect_with_connector(service_fn(move |_: Uri| {
+                    tokio::net::UnixStream::connect(path.clone())
+                }))
+                .await?
+        }
+        None => endpoint.connect().await?,
+    };
+    Ok(channel)
+}
+
+// Client interceptor presenting the shared token, if there is one, as a bearer token
+#[derive(Clone, Debug, Default)]
+pub struct BearerToken(Option<MetadataValue<Ascii>>);
+
+impl BearerToken {
+    pub fn new(token: Option<&str>) -> Result<BearerToken, String> {
+        token
+            .map(|token| {
+                format!("Bearer {token}")
+                    .parse()
+                    .map_err(|_| format!("{TOKEN_VAR} may only contain printable ASCII"))
+            })
+            .transpose()
+            .map(BearerToken)
+    }
+}
+impl Interceptor for BearerToken {
+    fn call(&mut self, mut request: Request<()>) -> Result<Request<()>, Status> {
+        if let Some(value) = &self.0 {
+            request
+                .metadata_mut()
+                .insert("authorization", value.clone());
+        }
+        Ok(request)
+    }
+}
+
+// Server interceptor rejecting requests that do not present the shared token, if there is one
+#[derive(Clone, Debug, Default)]
+pub struct CheckToken(Option<String>);
+
+impl CheckToken {
+    pub fn new(token: Option<&str>) -> CheckToken {
+        CheckToken(token.map(str::to_string))
+    }
+}
+impl Interceptor for CheckToken {
+    fn call(&mut self, request: Request<()>) -> Result<Request<()>, Status> {
+        match &self.0 {
+            Some(token) if !presents_token(&request, token) => {
+                Err(Status::unauthenticated("Missing or invalid token."))
+            }
+            _ => Ok(request),
+        }
+    }
+}
+
+// Whether a request presents `token` as a bearer token, for services whose token depends on the
+// request
+pub fn presents_token<T>(request: &Request<T>, token: &str) -> bool {
+    let presented = request
+        .metadata()
+        .get("authorization")
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or("");
+    constant_time_eq(presented.as_bytes(), format!("Bearer {token}").as_bytes())
+}
+
+// Compares two byte strings in time that does not depend on where they differ, so that tokens
+// cannot be guessed a byte at a time
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+// Connection accepted on a Unix domain socket, which tonic servers do not accept out of the box
+#[derive(Debug)]
+pub struct UnixStream(tokio::net::UnixStream);
+
+impl Connected for UnixStream {
+    type ConnectInfo = ();
+
+    fn connect_info(&self) -> Self::ConnectInfo {}
+}
+impl AsyncRead for UnixStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.0).poll_read(cx, buf)
+    }
+}
+impl AsyncWrite for UnixStream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.0).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.0).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.0).poll_shutdown(cx)
+    }
+}
+
+// Listens on a Unix domain socket that only the current user can connect to, returning the
+// stream of accepted connections. A socket left behind by a server that has exited is
+// replaced, but one that is still in use is not.
+pub fn bind_unix(path: &Path) -> io::Result<impl Stream<Item = io::Result<UnixStream>>> {
+    if let Ok(metadata) = fs::symlink_metadata(path) {
+        if !metadata.file_type().is_socket() {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("{} exists and is not a socket", path.display()),
+            ));
+        }
+        if net::UnixStream::connect(path).is_ok() {
+            return Err(io::Error::new(
+                io::ErrorKind::AddrInUse,
+                format!("{} is in use by another server", path.display()),
+            ));
+        }
+        fs::remove_file(path)?;
+    }
+
+    let listener = UnixListener::bind(path)?;
+    fs::set_permissions(path, fs::Permissions::from_mode(0o600))?;
+    Ok(UnixListenerStream::new(listener).map(|stream| stream.map(UnixStream)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_listen_addr() {
+        let addr: ListenAddr = "0.0.0.0:6000".parse().unwrap();
+        assert_eq!(addr.url(), "http://127.0.0.1:6000");
+        assert_eq!(addr.to_string(), "0.0.0.0:6000");
+        let addr: ListenAddr = "[::1]:50051".parse().unwrap();
+        assert_eq!(addr.url(), "http://[::1]:50051");
+
+        let addr: ListenAddr = "unix:/tmp/mrlite.sock".parse().unwrap();
+        assert_eq!(addr, ListenAddr::Unix(PathBuf::from("/tmp/mrlite.sock")));
+        assert_eq!(addr.url(), "unix:/tmp/mrlite.sock");
+        assert_eq!(addr.to_string().parse(), Ok(addr));
+
+        assert!("unix:".parse::<ListenAddr>().is_err());
+        assert!("localhost".parse::<ListenAddr>().is_err());
+    }
+
+    #[test]
+    fn test_token() {
+        let request = |token: Option<&str>| {
+            let mut bearer = BearerToken::new(token).unwrap();
+            bearer.call(Request::new(())).unwrap()
+        };
+        let mut check = CheckToken::new(Some("secret"));
+        assert!(check.call(request(Some("secret"))).is_ok());
+        assert!(check.call(request(Some("secrets"))).is_err());
+        assert!(check.call(request(None)).is_err());
+        assert!(presents_token(&request(Some("job-token")), "job-token"));
+        assert!(!presents_token(&request(Some("secret")), "job-token"));
+        assert!(!presents_token(&request(None), "job-token"));
+
+        // Without a token, every request is let through
+        assert!(CheckToken::new(None).call(request(None)).is_ok());
+        assert!(BearerToken::new(Some("line\nbreak")).is_err());
+    }
+}
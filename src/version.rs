@@ -0,0 +1,33 @@
+// Versions exchanged by the master and workers when a worker registers, so that incompatible
+// builds are rejected up front instead of misreading each other's messages. See the policy at
+// the top of proto/tasks.proto for when PROTOCOL_VERSION changes.
+
+// Version of the `tasks` protocol spoken by this build
+pub const PROTOCOL_VERSION: u32 = 4;
+
+// Oldest protocol version this build still interoperates with. Raised when a change drops
+// support for older peers: version 1 workers do not serve their map output, version 2 workers
+// do not say which job their reports are for, and version 3 workers fetch map output with the
+// master's token instead of the job's shuffle token.
+pub const MIN_PROTOCOL_VERSION: u32 = 4;
+
+// Version of this build, reported for diagnostics only
+pub const BUILD_VERSION: &str = env!("CARGO_PKG_VERSION");
+
+// Returns true if a peer speaking `protocol_version` can work with this build. Peers that
+// predate versioning send 0, which is never compatible.
+pub fn compatible(protocol_version: u32) -> bool {
+    (MIN_PROTOCOL_VERSION..=PROTOCOL_VERSION).contains(&protocol_version)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_compatible() {
+        assert!(compatible(PROTOCOL_VERSION));
+        assert!(!compatible(0));
+        assert!(!compatible(PROTOCOL_VERSION + 1));
+    }
+}